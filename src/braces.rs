@@ -0,0 +1,92 @@
+// Shell-style brace expansion for test patterns, which the glob crate doesn't
+// do itself: `tests/{ui,regression}/*.rs` becomes `tests/ui/*.rs` and
+// `tests/regression/*.rs`. Groups may nest. A brace or comma preceded by a
+// backslash is literal, as is a group without any comma.
+pub fn expand(pattern: &str) -> Vec<String> {
+    let Some((open, close, commas)) = first_group(pattern) else {
+        return vec![unescape(pattern)];
+    };
+
+    let prefix = &pattern[..open];
+    let suffix = &pattern[close + 1..];
+    let mut bounds = vec![open];
+    bounds.extend(commas);
+    bounds.push(close);
+
+    let mut expanded = Vec::new();
+    for alternative in bounds.windows(2).map(|w| &pattern[w[0] + 1..w[1]]) {
+        expanded.extend(expand(&format!("{prefix}{alternative}{suffix}")));
+    }
+    expanded
+}
+
+// Finds the first brace group that has at least one comma at its own level,
+// returning the positions of its braces and of those commas.
+fn first_group(pattern: &str) -> Option<(usize, usize, Vec<usize>)> {
+    let bytes = pattern.as_bytes();
+    let mut start = 0;
+    while let Some(open) = find_unescaped(bytes, start, b'{') {
+        let mut depth = 0;
+        let mut commas = Vec::new();
+        let mut i = open;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\\' => i += 1,
+                b'{' => depth += 1,
+                b'}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        if !commas.is_empty() {
+                            return Some((open, i, commas));
+                        }
+                        break;
+                    }
+                }
+                b',' if depth == 1 => commas.push(i),
+                _ => {}
+            }
+            i += 1;
+        }
+        start = open + 1;
+    }
+    None
+}
+
+fn find_unescaped(bytes: &[u8], mut i: usize, target: u8) -> Option<usize> {
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            byte if byte == target => return Some(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+fn unescape(pattern: &str) -> String {
+    let mut unescaped = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.next() {
+                Some(next @ ('{' | '}' | ',')) => unescaped.push(next),
+                Some(next) => {
+                    unescaped.push('\\');
+                    unescaped.push(next);
+                }
+                None => unescaped.push('\\'),
+            },
+            _ => unescaped.push(ch),
+        }
+    }
+    unescaped
+}
+
+#[test]
+fn test_expand() {
+    assert_eq!(expand("tests/ui/*.rs"), ["tests/ui/*.rs"]);
+    assert_eq!(expand("tests/{ui,regression}/*.rs"), ["tests/ui/*.rs", "tests/regression/*.rs"]);
+    assert_eq!(expand("{a,b{c,d}}/{x,y}"), ["a/x", "a/y", "bc/x", "bc/y", "bd/x", "bd/y"]);
+    assert_eq!(expand("{a}/\\{b,c\\}/{d,}"), ["{a}/{b,c}/d", "{a}/{b,c}/"]);
+}
@@ -0,0 +1,105 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    ffi::OsStr,
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Output},
+    time::UNIX_EPOCH,
+};
+
+// Build results are keyed on everything that can influence them: the test
+// source (all of it for a directory crate), the modification times of the
+// program that is run and of `inputs`, such as the driver behind a wrapper or
+// an extern rlib, and the full driver command line including the backend and
+// any extra arguments. The name of the output binary is left out so that
+// renumbering tests doesn't invalidate the cache.
+pub fn key(cmd: &Command, source: &Path, inputs: &[&Path]) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    hash_source(source, &mut hasher).ok()?;
+
+    let program = Path::new(cmd.get_program());
+    for path in [program].iter().chain(inputs) {
+        path.hash(&mut hasher);
+        if let Ok(modified) = fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            modified.duration_since(UNIX_EPOCH).ok()?.hash(&mut hasher);
+        }
+    }
+
+    let mut args = cmd.get_args();
+    while let Some(arg) = args.next() {
+        arg.hash(&mut hasher);
+        if arg == OsStr::new("-o") {
+            args.next();
+        }
+    }
+    for (key, value) in cmd.get_envs() {
+        (key, value).hash(&mut hasher);
+    }
+
+    Some(hasher.finish())
+}
+
+// Hashes a file, or every file under a directory along with its relative path.
+fn hash_source(path: &Path, hasher: &mut DefaultHasher) -> io::Result<()> {
+    if !path.is_dir() {
+        fs::read(path)?.hash(hasher);
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(fs::DirEntry::file_name);
+    for entry in entries {
+        entry.file_name().hash(hasher);
+        hash_source(&entry.path(), hasher)?;
+    }
+    Ok(())
+}
+
+fn entry(artifacts: &Path, key: u64) -> PathBuf {
+    artifacts.join("cache").join(format!("{:016x}", key))
+}
+
+// On a hit, the cached binary (if the build produced one) is copied back to
+// where the driver would have written it.
+pub fn load(artifacts: &Path, key: u64, name: &str) -> Option<Output> {
+    let entry = entry(artifacts, key);
+    let code = fs::read_to_string(entry.join("status")).ok()?.trim().parse().ok()?;
+    let stdout = fs::read(entry.join("stdout")).ok()?;
+    let stderr = fs::read(entry.join("stderr")).ok()?;
+    let binary = entry.join("bin");
+    if binary.exists() {
+        fs::copy(binary, artifacts.join(name)).ok()?;
+    }
+    Some(Output { status: exit_status(code), stdout, stderr })
+}
+
+pub fn store(artifacts: &Path, key: u64, name: &str, output: &Output) -> io::Result<()> {
+    let code = match output.status.code() {
+        Some(code) => code,
+        // Killed by a signal; not worth caching.
+        None => return Ok(()),
+    };
+    let entry = entry(artifacts, key);
+    fs::create_dir_all(&entry)?;
+    fs::write(entry.join("stdout"), &output.stdout)?;
+    fs::write(entry.join("stderr"), &output.stderr)?;
+    let binary = artifacts.join(name);
+    if output.status.success() && binary.exists() {
+        fs::copy(binary, entry.join("bin"))?;
+    }
+    // Written last so that a partially written entry is never loaded.
+    fs::write(entry.join("status"), code.to_string())
+}
+
+#[cfg(unix)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw((code & 0xff) << 8)
+}
+
+#[cfg(windows)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(code as u32)
+}
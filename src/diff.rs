@@ -1,5 +1,17 @@
 pub use self::r#impl::Diff;
 
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    pub max_len: usize,
+    pub min_similarity: f64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits { max_len: 2048, min_similarity: 0.8 }
+    }
+}
+
 pub enum Render<'a> {
     Common(&'a str),
     Unique(&'a str),
@@ -7,7 +19,7 @@ pub enum Render<'a> {
 
 mod r#impl {
     use {
-        super::Render,
+        super::{Limits, Render},
         dissimilar::Chunk,
         std::{cmp, panic},
     };
@@ -19,14 +31,14 @@ mod r#impl {
     }
 
     impl<'a> Diff<'a> {
-        pub fn compute(expected: &'a str, actual: &'a str) -> Option<Self> {
-            if expected.len() + actual.len() > 2048 {
+        pub fn compute(expected: &'a str, actual: &'a str, limits: Limits) -> Option<Self> {
+            if expected.len() + actual.len() > limits.max_len {
                 // We don't yet trust the dissimilar crate to work well on large
                 // inputs.
                 return None;
             }
 
-            // Nor on non-ascii inputs.
+            // Nor on non-ascii inputs, so fall back to no diff if it panics.
             let diff = panic::catch_unwind(|| dissimilar::diff(expected, actual)).ok()?;
 
             let mut common_len = 0;
@@ -37,7 +49,7 @@ mod r#impl {
             }
 
             let bigger_len = cmp::max(expected.len(), actual.len());
-            let worth_printing = 5 * common_len >= 4 * bigger_len;
+            let worth_printing = common_len as f64 >= limits.min_similarity * bigger_len as f64;
             if !worth_printing {
                 return None;
             }
@@ -22,7 +22,7 @@ impl Directory {
         env::current_dir().map(Directory::new)
     }
 
-    pub fn to_string_lossy(&self) -> Cow<str> {
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
         self.path.to_string_lossy()
     }
 
@@ -1,13 +1,23 @@
 use {
     crate::error::{Error, Result},
-    std::env,
+    serde_derive::Deserialize,
+    std::{env, ffi::OsString, path::PathBuf},
+    termcolor::ColorChoice,
 };
 
+// Set through the TRYBUILD environment variable. Combine it with a
+// `trybuild=<path>` filter to only update the files of the matching tests.
 #[derive(PartialEq, Debug, Default)]
 pub enum Update {
+    // Write missing expected output to the `wip` directory.
     #[default]
     Wip,
+    // `TRYBUILD=overwrite` or `TRYBUILD=bless`: create or rewrite expected
+    // output files to match the actual output.
     Overwrite,
+    // `TRYBUILD=overwrite-missing`: create expected output files that don't
+    // exist yet, but report mismatches against existing ones as usual.
+    OverwriteNew,
 }
 
 impl Update {
@@ -19,8 +29,206 @@ impl Update {
 
         match var.as_os_str().to_str() {
             Some("wip") => Ok(Update::Wip),
-            Some("overwrite") => Ok(Update::Overwrite),
+            Some("overwrite" | "bless") => Ok(Update::Overwrite),
+            Some("overwrite-missing") => Ok(Update::OverwriteNew),
             _ => Err(Error::UpdateVar(var)),
         }
     }
 }
+
+pub fn driver() -> Option<PathBuf> {
+    env::var_os("TRYBUILD_DRIVER").filter(|var| !var.is_empty()).map(PathBuf::from)
+}
+
+pub fn sysroot() -> Option<PathBuf> {
+    env::var_os("TRYBUILD_SYSROOT").filter(|var| !var.is_empty()).map(PathBuf::from)
+}
+
+pub fn driver_wrapper() -> Option<PathBuf> {
+    env::var_os("TRYBUILD_DRIVER_WRAPPER").filter(|var| !var.is_empty()).map(PathBuf::from)
+}
+
+// The directory of the crate under test, if it differs from the current
+// directory.
+pub fn manifest_dir() -> Option<PathBuf> {
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR")?);
+    let current_dir = env::current_dir().ok()?;
+    let same = match (manifest_dir.canonicalize(), current_dir.canonicalize()) {
+        (Ok(manifest_dir), Ok(current_dir)) => manifest_dir == current_dir,
+        _ => manifest_dir == current_dir,
+    };
+    if same {
+        None
+    } else {
+        Some(manifest_dir)
+    }
+}
+
+pub fn timings() -> bool {
+    flag("TRYBUILD_TIMINGS")
+}
+
+pub fn verbose() -> bool {
+    flag("TRYBUILD_VERBOSE")
+}
+
+pub fn quiet() -> bool {
+    flag("TRYBUILD_QUIET")
+}
+
+pub fn dry_run() -> bool {
+    flag("TRYBUILD_DRY_RUN")
+}
+
+// Skip the remaining backends once one of them had a failing test.
+pub fn stop_after_backend_fail() -> bool {
+    flag("TRYBUILD_STOP_AFTER_BACKEND_FAIL")
+}
+
+// `TRYBUILD_COLOR=never|always|auto`, falling back to Cargo's own
+// `CARGO_TERM_COLOR` and then to the `NO_COLOR` convention. Output is printed
+// before there is anywhere to report a bad value, so anything unrecognized
+// means `auto`.
+pub fn color() -> ColorChoice {
+    let choice = |name| match env::var_os(name) {
+        Some(var) if var == "never" => Some(ColorChoice::Never),
+        Some(var) if var == "always" => Some(ColorChoice::Always),
+        Some(var) if var == "auto" => Some(ColorChoice::Auto),
+        _ => None,
+    };
+    if let Some(choice) = choice("TRYBUILD_COLOR").or_else(|| choice("CARGO_TERM_COLOR")) {
+        return choice;
+    }
+    if env::var_os("NO_COLOR").is_some_and(|var| !var.is_empty()) {
+        ColorChoice::Never
+    } else {
+        ColorChoice::Auto
+    }
+}
+
+fn flag(name: &str) -> bool {
+    env::var_os(name).is_some_and(|var| !var.is_empty() && var != "0")
+}
+
+// Whether `TRYBUILD_REPORT=json` asks for a report in the output directory.
+pub fn report() -> Result<bool> {
+    let var = match env::var_os("TRYBUILD_REPORT") {
+        Some(var) => var,
+        None => return Ok(false),
+    };
+
+    match var.as_os_str().to_str() {
+        Some("json") => Ok(true),
+        _ => Err(Error::ReportVar(var)),
+    }
+}
+
+// How many existing expected output files `TRYBUILD=overwrite` may rewrite in
+// one run: `TRYBUILD_OVERWRITE_LIMIT`, by default 10. `TRYBUILD_OVERWRITE_ALL`
+// confirms that any number of them may change.
+pub fn overwrite_limit() -> Result<Option<usize>> {
+    if flag("TRYBUILD_OVERWRITE_ALL") {
+        return Ok(None);
+    }
+    match env::var_os("TRYBUILD_OVERWRITE_LIMIT") {
+        Some(var) => match var.to_str().and_then(|var| var.trim().parse().ok()) {
+            Some(limit) => Ok(Some(limit)),
+            None => Err(Error::OverwriteLimitVar(var)),
+        },
+        None => Ok(Some(10)),
+    }
+}
+
+// `TRYBUILD_SHARD=k/n` keeps the tests whose index is `k` modulo `n`, with
+// `k` counting from 0. Indices follow the sorted order of glob matches, so
+// the `n` shards are disjoint and the same on every machine.
+pub fn shard() -> Result<Option<(usize, usize)>> {
+    let var = match env::var_os("TRYBUILD_SHARD") {
+        Some(var) if !var.is_empty() => var,
+        _ => return Ok(None),
+    };
+
+    let shard = var.to_str().and_then(|var| {
+        let (k, n) = var.split_once('/')?;
+        let (k, n) = (k.trim().parse().ok()?, n.trim().parse().ok()?);
+        (k < n).then_some((k, n))
+    });
+    shard.map(Some).ok_or(Error::ShardVar(var))
+}
+
+// The name of the lock file in the project directory. Suites of different
+// crates don't share artifacts, so each crate gets its own lock and they
+// don't wait for each other.
+pub fn lock_name() -> OsString {
+    let mut name = OsString::from(".lock");
+    if let Some(krate) = env::var_os("CARGO_PKG_NAME") {
+        name.push("-");
+        name.push(krate);
+    }
+    name
+}
+
+// `trybuild/<crate>` in the target directory. Without `CARGO_TARGET_DIR`, the
+// target directory is found from the test binary, which Cargo puts in
+// `<target>/<profile>/deps`. Failing both, `.artifacts` in the current
+// directory.
+pub fn out_dir() -> PathBuf {
+    let target_dir = env::var_os("CARGO_TARGET_DIR").map(PathBuf::from).or_else(|| {
+        let exe = env::current_exe().ok()?;
+        let deps = exe.parent().filter(|dir| dir.ends_with("deps"))?;
+        Some(deps.parent()?.parent()?.to_owned())
+    });
+    match target_dir {
+        Some(target_dir) => {
+            let krate = env::var_os("CARGO_PKG_NAME").unwrap_or_else(|| OsString::from("tests"));
+            target_dir.join("trybuild").join(krate)
+        }
+        None => PathBuf::from(".artifacts"),
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Cranelift,
+    Llvm,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 2] = [Backend::Cranelift, Backend::Llvm];
+
+    pub fn codegen(self) -> &'static str {
+        match self {
+            Backend::Cranelift => "cranelift",
+            Backend::Llvm => "llvm",
+        }
+    }
+
+    pub fn display(self) -> &'static str {
+        match self {
+            Backend::Cranelift => "Cranelift",
+            Backend::Llvm => "LLVM",
+        }
+    }
+
+    pub fn env() -> Result<Vec<Self>> {
+        let var = match env::var_os("TRYBUILD_BACKENDS") {
+            Some(var) => var,
+            None => return Ok(Backend::ALL.to_vec()),
+        };
+
+        let list = var.to_str().ok_or_else(|| Error::BackendsVar(var.clone()))?;
+        let mut backends = Vec::new();
+        for name in list.split(',').map(str::trim) {
+            let backend = match name {
+                "cranelift" => Backend::Cranelift,
+                "llvm" => Backend::Llvm,
+                _ => return Err(Error::BackendsVar(OsString::from(name))),
+            };
+            if !backends.contains(&backend) {
+                backends.push(backend);
+            }
+        }
+        Ok(backends)
+    }
+}
@@ -5,28 +5,59 @@ use {
         fmt::{self, Display},
         io,
         path::PathBuf,
+        time::Duration,
     },
 };
 
 #[derive(Debug)]
 pub enum Error {
+    BackendsDisagree(PathBuf),
+    BackendsVar(OsString),
+    BadPattern(String, PatternError),
+    BeforeAll(String),
     Cargo(io::Error),
     CargoFail,
-    GetManifest(PathBuf, Box<Error>),
+    CleanOutDir(PathBuf),
+    ConflictingExpectation(PathBuf),
+    DriverBuildFailed(String),
+    DriverIce(PathBuf),
+    DriverNotFound(PathBuf),
+    DuplicateTest(PathBuf),
+    EmptyGlob(String),
+    EnvFile(PathBuf, String),
+    ExitFile(PathBuf, String),
+    Flaky(PathBuf),
     Glob(GlobError),
+    InlineAndStderr(PathBuf, PathBuf),
     Io(io::Error),
-    Metadata(serde_json::Error),
+    Manifest(PathBuf, String),
     Mismatch,
-    NoWorkspaceManifest,
+    MissingArtifact(PathBuf),
+    MissingErrorCode(String),
+    NoReference(PathBuf),
+    NotSilent,
     Open(PathBuf, io::Error),
+    OrphanedStderr(PathBuf),
+    OverwriteLimit(usize, usize),
+    OverwriteLimitVar(OsString),
     Pattern(PatternError),
-    ProjectDir,
     ReadStderr(io::Error),
+    ReadStdout(io::Error),
+    ReportVar(OsString),
     RunFailed,
+    RunSucceeded,
+    ShardVar(OsString),
     ShouldNotHaveCompiled,
+    Timeout(PathBuf, String, Duration),
     Toml(basic_toml::Error),
+    UnusedStderr(PathBuf),
     UpdateVar(OsString),
+    WarningCount(usize, usize),
+    WarningCountUnsupported(PathBuf),
+    WarningsFile(PathBuf, String),
     WriteStderr(io::Error),
+    WriteStdout(io::Error),
+    WriteReport(PathBuf, io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -36,31 +67,137 @@ impl Display for Error {
         use self::Error::*;
 
         match self {
+            BackendsDisagree(path) => {
+                write!(f, "{}: backends report different errors", path.display())
+            }
+            BackendsVar(var) => {
+                write!(f, "unrecognized backend in TRYBUILD_BACKENDS: {:?}", var.to_string_lossy())
+            }
+            BadPattern(pattern, e) => write!(f, "invalid glob pattern `{}`: {}", pattern, e),
+            BeforeAll(e) => write!(f, "before_all failed: {}", e),
             Cargo(e) => write!(f, "failed to execute cargo: {}", e),
             CargoFail => write!(f, "cargo reported an error"),
-            GetManifest(path, e) => write!(f, "failed to read manifest {}: {}", path.display(), e),
+            CleanOutDir(path) => write!(
+                f,
+                "refusing to clean {}, which trybuild did not create; delete it by hand or pick a new out_dir",
+                path.display(),
+            ),
+            ConflictingExpectation(path) => write!(
+                f,
+                "{} is registered more than once with different expectations; \
+                 register it on its own after the patterns matching it to say which \
+                 one applies",
+                path.display(),
+            ),
+            DriverBuildFailed(stderr) => {
+                write!(f, "failed to build the driver package:\n{}", stderr.trim_end())
+            }
+            DriverIce(path) => write!(f, "{}: the driver crashed", path.display()),
+            DriverNotFound(path) => write!(
+                f,
+                "driver not found at {}; set TRYBUILD_DRIVER to the path of the driver binary",
+                path.display(),
+            ),
+            DuplicateTest(path) => {
+                write!(
+                    f,
+                    "{} is registered more than once with different expectations",
+                    path.display()
+                )
+            }
+            EmptyGlob(pattern) => write!(f, "pattern `{}` matched no files", pattern),
+            EnvFile(path, line) => {
+                write!(f, "{}: expected KEY=VALUE, found {:?}", path.display(), line)
+            }
+            ExitFile(path, contents) => {
+                write!(f, "{}: expected an exit code, found {:?}", path.display(), contents)
+            }
+            Flaky(path) => {
+                write!(f, "{}: output differs between builds of the same test", path.display())
+            }
             Glob(e) => write!(f, "{}", e),
+            InlineAndStderr(path, stderr_path) => write!(
+                f,
+                "{}: has an `// EXPECTED:` block and also {}; remove one of them",
+                path.display(),
+                stderr_path.display(),
+            ),
             Io(e) => write!(f, "{}", e),
-            Metadata(e) => write!(f, "failed to read cargo metadata: {}", e),
+            Manifest(path, e) => write!(f, "{}: {}", path.display(), e),
             Mismatch => write!(f, "compiler error does not match expected error"),
-            NoWorkspaceManifest => write!(
+            MissingArtifact(path) => {
+                write!(f, "the driver succeeded but did not write {}", path.display())
+            }
+            MissingErrorCode(code) => write!(f, "compiler error does not contain {}", code),
+            NoReference(path) => write!(
                 f,
-                "Cargo.toml uses edition.workspace=true, \
-                but no edition found in workspace's manifest"
+                "{}: LLVM didn't build this test earlier in the run, so there are no errors to match",
+                path.display(),
             ),
+            NotSilent => write!(f, "expected test case to print nothing, but it printed output"),
             Open(path, e) => write!(f, "{}: {}", path.display(), e),
+            OrphanedStderr(path) => {
+                write!(f, "{}: no registered test uses this file", path.display())
+            }
+            OverwriteLimit(count, limit) => write!(
+                f,
+                "{} expected output files would change, more than the limit of {}, so none \
+                 were overwritten; set TRYBUILD_OVERWRITE_ALL=1 to overwrite all of them",
+                count, limit,
+            ),
+            OverwriteLimitVar(var) => {
+                write!(f, "TRYBUILD_OVERWRITE_LIMIT must be a number: {:?}", var.to_string_lossy())
+            }
             Pattern(e) => write!(f, "{}", e),
-            ProjectDir => write!(f, "failed to determine name of project dir"),
             ReadStderr(e) => write!(f, "failed to read stderr file: {}", e),
+            ReadStdout(e) => write!(f, "failed to read stdout file: {}", e),
+            ReportVar(var) => {
+                write!(f, "unrecognized value of TRYBUILD_REPORT: {:?}", var.to_string_lossy())
+            }
             RunFailed => write!(f, "execution of the test case was unsuccessful"),
+            RunSucceeded => write!(f, "expected test case to fail at runtime, but it succeeded"),
+            ShardVar(var) => {
+                write!(
+                    f,
+                    "TRYBUILD_SHARD must look like `k/n` with k < n: {:?}",
+                    var.to_string_lossy()
+                )
+            }
             ShouldNotHaveCompiled => {
                 write!(f, "expected test case to fail to compile, but it succeeded")
             }
+            Timeout(path, codegen, timeout) => write!(
+                f,
+                "{} did not finish within {}s under the {} backend and was killed",
+                path.display(),
+                timeout.as_secs_f32(),
+                codegen,
+            ),
             Toml(e) => write!(f, "{}", e),
+            UnusedStderr(path) => write!(
+                f,
+                "{}: the test is expected to pass, so this file is never compared",
+                path.display(),
+            ),
             UpdateVar(var) => {
                 write!(f, "unrecognized value of TRYBUILD: {:?}", var.to_string_lossy(),)
             }
+            WarningCount(expected, actual) => {
+                write!(f, "expected {} warnings, but the compiler reported {}", expected, actual)
+            }
+            WarningCountUnsupported(path) => write!(
+                f,
+                "{}: the number of warnings is not checked for run-fail tests",
+                path.display(),
+            ),
+            WarningsFile(path, contents) => {
+                write!(f, "{}: expected a number of warnings, found {:?}", path.display(), contents)
+            }
             WriteStderr(e) => write!(f, "failed to write stderr file: {}", e),
+            WriteStdout(e) => write!(f, "failed to write stdout file: {}", e),
+            WriteReport(path, e) => {
+                write!(f, "failed to write report {}: {}", path.display(), e)
+            }
         }
     }
 }
@@ -69,7 +206,20 @@ impl Error {
     pub fn already_printed(&self) -> bool {
         use self::Error::*;
 
-        matches!(self, CargoFail | Mismatch | RunFailed | ShouldNotHaveCompiled)
+        matches!(
+            self,
+            BackendsDisagree(_)
+                | CargoFail
+                | DriverIce(_)
+                | Flaky(_)
+                | Mismatch
+                | MissingErrorCode(_)
+                | NotSilent
+                | RunFailed
+                | RunSucceeded
+                | ShouldNotHaveCompiled
+                | WarningCount(..)
+        )
     }
 }
 
@@ -1,5 +1,5 @@
 use {
-    crate::error::Result,
+    crate::{error::Result, message},
     once_cell::sync::OnceCell,
     std::{
         fs::{self, File, OpenOptions},
@@ -7,7 +7,7 @@ use {
         path::{Path, PathBuf},
         sync::{
             atomic::{AtomicBool, Ordering},
-            Arc, Mutex, MutexGuard, PoisonError,
+            Arc, Mutex, MutexGuard, Once, PoisonError,
         },
         thread,
         time::{Duration, SystemTime},
@@ -15,6 +15,7 @@ use {
 };
 
 static LOCK: OnceCell<Mutex<()>> = OnceCell::new();
+static READ_ONLY: Once = Once::new();
 
 pub struct Lock {
     intraprocess_guard: Guard,
@@ -95,6 +96,12 @@ fn create(path: &Path) -> Option<File> {
             Err(io_error) => match io_error.kind() {
                 // Lock is already held by another test.
                 io::ErrorKind::AlreadyExists => {}
+                // Read-only source tree. Carry on without the lock, but say so
+                // since concurrent test crates may now step on each other.
+                io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+                    READ_ONLY.call_once(|| message::lock_unavailable(path, &io_error));
+                    return None;
+                }
                 // File based locking isn't going to work for some reason.
                 _ => return None,
             },
@@ -0,0 +1,67 @@
+// Expected output kept in the test itself, for tests too small to deserve a
+// `.stderr` file. It is a block of line comments following a line that is
+// just `// EXPECTED:`, and ends at the first line that is not a comment:
+//
+//     // EXPECTED:
+//     // error[E0308]: mismatched types
+//     //  --> $DIR/foo.rs:2:18
+//
+// One space after each `//` is dropped.
+
+const MARKER: &str = "// EXPECTED:";
+
+pub fn expected(source: &str) -> Option<String> {
+    let (start, end) = block(source)?;
+    let mut expected = String::new();
+    for line in source[start..end].lines() {
+        let line = line.trim_start().trim_start_matches("//");
+        expected += line.strip_prefix(' ').unwrap_or(line);
+        expected.push('\n');
+    }
+    Some(expected)
+}
+
+// The source with the expected block rewritten to hold `actual`.
+pub fn replace(source: &str, actual: &str) -> String {
+    let Some((start, end)) = block(source) else {
+        return source.to_owned();
+    };
+    let mut replaced = source[..start].to_owned();
+    for line in actual.lines() {
+        replaced += "//";
+        if !line.is_empty() {
+            replaced.push(' ');
+            replaced += line;
+        }
+        replaced.push('\n');
+    }
+    replaced += &source[end..];
+    replaced
+}
+
+// Byte range of the comment lines after the marker.
+fn block(source: &str) -> Option<(usize, usize)> {
+    let mut offset = 0;
+    let mut start = None;
+    for line in source.split_inclusive('\n') {
+        match start {
+            None if line.trim() == MARKER => start = Some(offset + line.len()),
+            Some(start) if !line.trim_start().starts_with("//") => return Some((start, offset)),
+            _ => {}
+        }
+        offset += line.len();
+    }
+    start.map(|start| (start, source.len()))
+}
+
+#[test]
+fn test_inline() {
+    let source = "fn main() {}\n\n// EXPECTED:\n// error: a\n//\n//  --> b\nconst X: u8 = 0;\n";
+    assert_eq!(expected(source).unwrap(), "error: a\n\n --> b\n");
+    assert_eq!(expected("fn main() {}\n"), None);
+    assert_eq!(
+        replace(source, "error: c\n"),
+        "fn main() {}\n\n// EXPECTED:\n// error: c\nconst X: u8 = 0;\n",
+    );
+    assert_eq!(expected("// EXPECTED:\n// error: a").unwrap(), "error: a\n");
+}
@@ -0,0 +1,111 @@
+use {
+    serde::{Deserialize, Deserializer},
+    serde_derive::{Deserialize, Serialize},
+};
+
+// Diagnostics from a driver run with `--error-format json`, one object per
+// line. Only the parts that say what was reported and where are kept, so a
+// baseline doesn't change with the way the driver renders them.
+
+#[derive(Deserialize, Serialize)]
+struct Diagnostic {
+    level: String,
+    #[serde(default, deserialize_with = "code", skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    message: String,
+    spans: Vec<Span>,
+    children: Vec<Diagnostic>,
+    #[serde(skip_serializing)]
+    rendered: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Span {
+    file_name: String,
+    line_start: usize,
+    column_start: usize,
+    line_end: usize,
+    column_end: usize,
+    is_primary: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+}
+
+// The driver writes `"code": {"code": "E0308", "explanation": ...}`.
+fn code<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    struct Code {
+        code: String,
+    }
+    Ok(Option::<Code>::deserialize(deserializer)?.map(|code| code.code))
+}
+
+// The structured form compared against the expected output, one pretty
+// printed object per diagnostic. Lines that aren't diagnostics, such as a
+// panic message, are kept as they are.
+pub fn structured(stderr: &str) -> String {
+    let mut out = String::new();
+    for line in stderr.lines() {
+        match serde_json::from_str::<Diagnostic>(line) {
+            Ok(diagnostic) => {
+                out += &serde_json::to_string_pretty(&diagnostic).unwrap_or_default();
+                out.push('\n');
+            }
+            Err(_) if line.starts_with('{') => {}
+            Err(_) => {
+                out += line;
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+// The diagnostics as the driver would have printed them without
+// `--error-format json`, for output that is shown rather than compared.
+pub fn rendered(stderr: &str) -> String {
+    let mut out = String::new();
+    for line in stderr.lines() {
+        match serde_json::from_str::<Diagnostic>(line) {
+            Ok(diagnostic) => out += diagnostic.rendered.as_deref().unwrap_or_default(),
+            Err(_) if line.starts_with('{') => {}
+            Err(_) => {
+                out += line;
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn test_structured() {
+    let stderr = concat!(
+        r#"{"$message_type":"diagnostic","message":"mismatched types","code":{"code":"E0308","explanation":"..."},"level":"error","spans":[{"file_name":"src/main.rs","byte_start":20,"byte_end":25,"line_start":2,"line_end":2,"column_start":18,"column_end":23,"is_primary":true,"text":[],"label":"expected `u8`","suggested_replacement":null,"expansion":null}],"children":[],"rendered":"error[E0308]: mismatched types\n"}"#,
+        "\n",
+        r#"{"$message_type":"artifact","artifact":"main.d","emit":"dep-info"}"#,
+        "\n",
+        "thread 'main' panicked\n",
+    );
+    let expected = r#"{
+  "level": "error",
+  "code": "E0308",
+  "message": "mismatched types",
+  "spans": [
+    {
+      "file_name": "src/main.rs",
+      "line_start": 2,
+      "column_start": 18,
+      "line_end": 2,
+      "column_end": 23,
+      "is_primary": true,
+      "label": "expected `u8`"
+    }
+  ],
+  "children": []
+}
+thread 'main' panicked
+"#;
+    assert_eq!(structured(stderr), expected);
+    assert_eq!(rendered(stderr), "error[E0308]: mismatched types\nthread 'main' panicked\n");
+}
@@ -1,8 +1,16 @@
+mod braces;
+mod cache;
 mod diff;
 mod error;
 mod flock;
+mod inline;
+mod json;
+mod manifest;
 mod message;
 mod normalize;
+mod report;
+mod unordered;
+mod variants;
 
 #[macro_use]
 mod path;
@@ -14,19 +22,33 @@ mod env;
 
 use {
     crate::{
+        diff::Limits as DiffLimits,
         directory::Directory,
-        env::Update,
+        env::{Backend, Update},
         error::Error,
         flock::Lock,
         message::{Fail, Warn},
+        normalize::Substitution,
+        report::Record,
+        variants::Variants,
     },
     std::{
         cell::RefCell,
-        collections::HashMap,
+        cmp,
+        collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+        error::Error as StdError,
         ffi::{OsStr, OsString},
+        fmt::{self, Debug},
         fs::{self, File},
+        io, mem, panic,
         path::{Path, PathBuf},
+        process::{self, Output},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex, PoisonError,
+        },
         thread,
+        time::{Duration, Instant},
     },
 };
 
@@ -38,6 +60,75 @@ pub struct TestCases {
 #[derive(Debug)]
 struct Runner {
     tests: Vec<Test>,
+    jobs: usize,
+    driver: Option<PathBuf>,
+    timeout: Option<Duration>,
+    allowed_slow: Vec<PathBuf>,
+    run_args: Vec<(PathBuf, Vec<OsString>)>,
+    warning_counts: Vec<(PathBuf, usize)>,
+    substitutions: Vec<Substitution>,
+    ignored_lines: Vec<Substitution>,
+    report_path: Option<PathBuf>,
+    junit_path: Option<PathBuf>,
+    records: Vec<Record>,
+    fail_fast: bool,
+    quiet: bool,
+    diff: DiffLimits,
+    clean: bool,
+    done: bool,
+    driver_args: Vec<OsString>,
+    driver_env: Vec<(OsString, OsString)>,
+    cache: bool,
+    prelude: Option<String>,
+    keep_artifacts_named: bool,
+    out_dir: Option<PathBuf>,
+    match_stdout: bool,
+    dry_run: bool,
+    check_orphaned_stderr: bool,
+    externs: Vec<(String, PathBuf)>,
+    opt_levels: Vec<String>,
+    edition: String,
+    retries: usize,
+    preserve_order: bool,
+    name_prefix: Option<String>,
+    json_diagnostics: bool,
+    before_all: Option<Hook<BeforeAll>>,
+    after_all: Option<Hook<AfterAll>>,
+    hooks_every_pass: bool,
+    excludes: Vec<PathBuf>,
+    report_excluded: bool,
+    unordered_stderr: bool,
+    references: References,
+    transform_stderr: Option<Arc<Hook<TransformStderr>>>,
+    snapshot_path: Option<PathBuf>,
+    snapshot: Snapshot,
+    // Shared with every pass, and with the snapshot compared after them, so
+    // that `TRYBUILD_OVERWRITE_LIMIT` applies to the run as a whole.
+    overwrites: Overwrites,
+}
+
+// Rewrites of existing expected output held back until the end of the run, by
+// path, with the error to report if writing the file fails.
+type Overwrites = Arc<Mutex<BTreeMap<PathBuf, (String, fn(io::Error) -> Error)>>>;
+
+// Output of the compile-fail tests by test path and pass, for `snapshot`.
+type Snapshot = Arc<Mutex<BTreeMap<(String, String), String>>>;
+
+// Errors from LLVM by test path and opt level, which the other backends of a
+// `compile_fail_match_reference` test must match.
+type References = Arc<Mutex<HashMap<(PathBuf, Option<String>), String>>>;
+
+type BeforeAll = dyn FnMut(&Project) -> Result<(), Box<dyn StdError>>;
+type AfterAll = dyn FnMut(&Project);
+type TransformStderr = dyn Fn(&str) -> String + Send + Sync;
+
+// A callback from the user, which can't be printed.
+struct Hook<F: ?Sized>(Box<F>);
+
+impl<F: ?Sized> Debug for Hook<F> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("Hook")
+    }
 }
 
 #[derive(Debug)]
@@ -49,60 +140,304 @@ struct ExpandedTest {
 }
 
 impl ExpandedTest {
-    fn run(&self, project: &Project, codegen: &str) -> Result<Outcome> {
-        self.test.run(project, &self.name, codegen)
+    fn run(self, project: &Project, codegen: &str, progress: (usize, usize)) -> Result<Outcome> {
+        match self.error {
+            Some(error) => {
+                let show_expected = project.has_pass && project.has_compile_fail;
+                message::begin_test(&self.test, &self.name, codegen, progress, show_expected);
+                Err(error)
+            }
+            None => self.test.run(project, &self.name, codegen, progress),
+        }
     }
 }
 
 struct ExpandedTestSet {
     vec: Vec<ExpandedTest>,
     path_to_index: HashMap<PathBuf, usize>,
+    preserve_order: bool,
+    // Pattern matches whose expectation an explicit registration changed.
+    overridden: Vec<Override>,
 }
 
+// A test path with the expectation of the pattern that matched it and the
+// one it was registered with on its own.
+type Override = (PathBuf, Expected, Expected);
+
 impl ExpandedTestSet {
-    fn new() -> Self {
-        ExpandedTestSet { vec: Vec::new(), path_to_index: HashMap::new() }
+    fn new(preserve_order: bool) -> Self {
+        ExpandedTestSet {
+            vec: Vec::new(),
+            path_to_index: HashMap::new(),
+            preserve_order,
+            overridden: Vec::new(),
+        }
     }
 
     fn insert(&mut self, test: Test, error: Option<Error>, is_from_glob: bool) {
         if let Some(&i) = self.path_to_index.get(&test.path) {
-            let prev = &mut self.vec[i];
-            if prev.is_from_glob {
-                prev.test.expected = test.expected;
+            let prev = &self.vec[i];
+            if prev.is_from_glob && !is_from_glob && prev.test.expected != test.expected {
+                self.overridden.push((test.path.clone(), prev.test.expected, test.expected));
+            }
+            if self.preserve_order && !is_from_glob && prev.is_from_glob {
+                // Move the test to where it was registered explicitly.
+                self.vec.remove(i);
+                for index in self.path_to_index.values_mut() {
+                    if *index > i {
+                        *index -= 1;
+                    }
+                }
+            } else {
+                let prev = &mut self.vec[i];
+                let differs = prev.test.expected != test.expected
+                    || prev.test.code != test.code
+                    || prev.test.agree != test.agree
+                    || prev.test.reference != test.reference
+                    || prev.test.silent != test.silent
+                    || prev.test.backends != test.backends;
+                if prev.is_from_glob && is_from_glob {
+                    // Neither pattern is more specific than the other.
+                    if differs {
+                        prev.error.get_or_insert(Error::ConflictingExpectation(test.path));
+                    }
+                    return;
+                }
+                if prev.is_from_glob {
+                    prev.is_from_glob = false;
+                    prev.error = None;
+                    prev.test.expected = test.expected;
+                    prev.test.code = test.code;
+                    prev.test.agree = test.agree;
+                    prev.test.reference = test.reference;
+                    prev.test.silent = test.silent;
+                    prev.test.backends = test.backends;
+                    return;
+                }
+                // A pattern matching a test registered on its own leaves it
+                // as it was registered, unless they disagree. Only a later
+                // explicit registration is taken to override a pattern.
+                if differs {
+                    prev.error.get_or_insert(if is_from_glob {
+                        Error::ConflictingExpectation(test.path)
+                    } else {
+                        Error::DuplicateTest(test.path)
+                    });
+                }
                 return;
             }
         }
 
-        let index = self.vec.len();
-        let name = format!("trybuild{:03}", index);
-        self.path_to_index.insert(test.path.clone(), index);
+        self.path_to_index.insert(test.path.clone(), self.vec.len());
+        let name = String::new();
         self.vec.push(ExpandedTest { name, test, error, is_from_glob });
     }
+
+    // Names follow the final order, once every test has found its place.
+    fn into_vec(self, name_prefix: Option<&str>) -> (Vec<ExpandedTest>, Vec<Override>) {
+        let mut vec = self.vec;
+        for (index, test) in vec.iter_mut().enumerate() {
+            test.name = test_name(name_prefix, index);
+        }
+        (vec, self.overridden)
+    }
 }
 
-impl Runner {
-    fn expand_globs(tests: &[Test]) -> Vec<ExpandedTest> {
-        let mut set = ExpandedTestSet::new();
+fn test_name(name_prefix: Option<&str>, index: usize) -> String {
+    match name_prefix {
+        Some(prefix) => format!("{prefix}_{index:03}"),
+        None => format!("trybuild{index:03}"),
+    }
+}
+
+// Adds an erroring entry for every `.stderr` file, shared or backend-specific,
+// and every `.stderr.contains` file in the directory of a registered test that
+// doesn't belong to any registered test. They go at the end so that the names
+// of real tests stay the same.
+fn add_orphaned_stderr(tests: &mut Vec<ExpandedTest>, name_prefix: Option<&str>) {
+    let owners: HashSet<PathBuf> = tests.iter().map(|t| t.test.path.with_extension("")).collect();
+    let dirs: BTreeSet<&Path> = tests.iter().filter_map(|t| t.test.path.parent()).collect();
+
+    let mut orphans = Vec::new();
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dir) else { continue };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(stem) = path.to_str().and_then(|path| {
+                path.strip_suffix(".stderr").or_else(|| path.strip_suffix(".stderr.contains"))
+            }) else {
+                continue;
+            };
+            let owner = Backend::ALL
+                .iter()
+                .find_map(|backend| stem.strip_suffix(&format!(".{}", backend.codegen())))
+                .unwrap_or(stem);
+            if !owners.contains(Path::new(owner)) {
+                orphans.push(path);
+            }
+        }
+    }
+
+    orphans.sort();
+    for path in orphans {
+        let name = test_name(name_prefix, tests.len());
+        let test = Test {
+            path: path.clone(),
+            expected: Expected::CompileFail,
+            code: None,
+            agree: false,
+            reference: false,
+            silent: false,
+            backends: Vec::new(),
+        };
+        let error = Some(Error::OrphanedStderr(path));
+        tests.push(ExpandedTest { name, test, error, is_from_glob: false });
+    }
+}
+
+// Names every test after its path, e.g. `tests_ui_foo` for `tests/ui/foo.rs`,
+// so that its artifacts keep their name when other tests come and go. Paths
+// that come out the same get `_2`, `_3`, ... in the order they were added.
+fn name_after_paths(tests: &mut [ExpandedTest]) {
+    let root = env::manifest_dir().or_else(|| std::env::current_dir().ok());
+    let mut used = HashSet::new();
+    for test in tests {
+        let path = test.test.path.with_extension("");
+        let path = root.as_ref().and_then(|root| path.strip_prefix(root).ok()).unwrap_or(&path);
+        let base: String = path
+            .to_string_lossy()
+            .chars()
+            .map(|ch| if ch.is_ascii_alphanumeric() { ch } else { '_' })
+            .collect();
+        let base = base.trim_matches('_');
+        let mut name = base.to_owned();
+        let mut suffix = 1;
+        while !used.insert(name.clone()) {
+            suffix += 1;
+            name = format!("{base}_{suffix}");
+        }
+        test.name = name;
+    }
+}
 
-        for test in tests {
-            match test.path.to_str() {
-                Some(utf8) if utf8.contains('*') => match glob(utf8) {
+impl Runner {
+    // Relative paths and patterns are resolved against the crate's manifest
+    // directory so that the same tests are found no matter where `cargo test`
+    // was started from.
+    fn expand_globs(&self) -> (Vec<ExpandedTest>, Vec<Override>) {
+        let mut set = ExpandedTestSet::new(self.preserve_order);
+        let root = env::manifest_dir();
+
+        for test in &self.tests {
+            let pattern = test.path.to_str().filter(|utf8| utf8.contains(['*', '{']));
+            let pattern = pattern.map(|pattern| resolve_pattern(root.as_deref(), pattern));
+            let test = &match &root {
+                Some(root) if test.path.is_relative() => {
+                    Test { path: root.join(&test.path), ..test.clone() }
+                }
+                _ => test.clone(),
+            };
+            match (test.path.to_str(), pattern) {
+                (Some(utf8), Some(pattern)) => match glob(&pattern, !self.preserve_order) {
+                    Ok(paths) if paths.is_empty() => {
+                        let error = Error::EmptyGlob(utf8.to_owned());
+                        set.insert(test.clone(), Some(error), false);
+                    }
                     Ok(paths) => {
-                        let expected = test.expected;
                         for path in paths {
-                            set.insert(Test { path, expected }, None, true);
+                            set.insert(Test { path, ..test.clone() }, None, true);
                         }
                     }
+                    Err(Error::Pattern(error)) => {
+                        let error = Error::BadPattern(utf8.to_owned(), error);
+                        set.insert(test.clone(), Some(error), false);
+                    }
                     Err(error) => set.insert(test.clone(), Some(error), false),
                 },
                 _ => set.insert(test.clone(), None, false),
             }
         }
 
-        set.vec
+        set.into_vec(self.name_prefix.as_deref())
+    }
+
+    // Removes the tests matching an `exclude` pattern, which like the tests is
+    // relative to the manifest directory, and returns them. The others keep
+    // the names they would have had without any exclusion.
+    fn exclude(&self, tests: &mut Vec<ExpandedTest>) -> Result<Vec<ExpandedTest>> {
+        let root = env::manifest_dir();
+        let mut literals = Vec::new();
+        let mut patterns = Vec::new();
+        for exclude in &self.excludes {
+            let pattern = exclude.to_str().filter(|utf8| utf8.contains(['*', '{']));
+            let pattern = pattern.map(|pattern| resolve_pattern(root.as_deref(), pattern));
+            let exclude = match &root {
+                Some(root) if exclude.is_relative() => root.join(exclude),
+                _ => exclude.clone(),
+            };
+            match (exclude.to_str(), pattern) {
+                (Some(utf8), Some(pattern)) => {
+                    for pattern in braces::expand(&pattern) {
+                        match glob::Pattern::new(&pattern) {
+                            Ok(pattern) => patterns.push(pattern),
+                            Err(error) => return Err(Error::BadPattern(utf8.to_owned(), error)),
+                        }
+                    }
+                }
+                _ => literals.push(exclude),
+            }
+        }
+
+        let (excluded, kept) = mem::take(tests).into_iter().partition(|t| {
+            literals.contains(&t.test.path)
+                || patterns.iter().any(|pattern| pattern.matches_path(&t.test.path))
+        });
+        *tests = kept;
+        Ok(excluded)
+    }
+
+    // Sharding goes by position in the expanded list, before any filter, so
+    // that a test stays in its shard whatever else is selected.
+    // Returns the tests left to the other shards.
+    fn shard(tests: &mut Vec<ExpandedTest>) -> Result<Vec<ExpandedTest>> {
+        let Some((k, n)) = env::shard()? else {
+            return Ok(Vec::new());
+        };
+        let mut skipped = Vec::new();
+        for (i, test) in mem::take(tests).into_iter().enumerate() {
+            if i % n == k {
+                tests.push(test);
+            } else {
+                skipped.push(test);
+            }
+        }
+        Ok(skipped)
+    }
+
+    // Removes the tests limited to backends other than `codegen`.
+    fn restrict(tests: &mut Vec<ExpandedTest>, codegen: &str) -> Vec<ExpandedTest> {
+        let (kept, skipped) = mem::take(tests).into_iter().partition(|t| {
+            t.test.backends.is_empty()
+                || t.test.backends.iter().any(|backend| backend.codegen() == codegen)
+        });
+        *tests = kept;
+        skipped
     }
 
-    fn filter(tests: &mut Vec<ExpandedTest>) {
+    // Filters come from `trybuild=` arguments after `cargo test --`. One
+    // argument may hold several whitespace-separated tokens, as in
+    // `trybuild="@fail @llvm"`, and the argument may be repeated, as in
+    // `trybuild=@fail trybuild=@llvm`; both forms mean the same.
+    // Tokens starting with `@` select by expected outcome (`@pass`, `@fail`,
+    // `@run-fail`, `@warn`) or by backend (`@cranelift`, `@llvm`). A token
+    // starting with `=`, as in `trybuild==tests/ui/foo.rs`, is the whole path
+    // or the `trybuildNNN` name of a test; anything else is a substring of the
+    // test path. Exact and substring tokens are of the same kind.
+    // Tokens of the same kind are alternatives, and the kinds are combined so
+    // that a test has to satisfy each kind that was given: `@pass @warn foo`
+    // is a pass or warn test whose path contains `foo`.
+    // Returns the tests that were filtered out.
+    fn filter(tests: &mut Vec<ExpandedTest>, codegen: &str) -> Vec<ExpandedTest> {
         let filters = std::env::args_os()
             .flat_map(OsString::into_string)
             .filter_map(|mut arg| {
@@ -114,22 +449,84 @@ impl Runner {
                 }
             })
             .collect::<Vec<String>>();
+        Self::filter_by(tests, codegen, &filters)
+    }
 
+    fn filter_by(
+        tests: &mut Vec<ExpandedTest>,
+        codegen: &str,
+        filters: &[String],
+    ) -> Vec<ExpandedTest> {
         if filters.is_empty() {
-            return;
+            return Vec::new();
+        }
+
+        let mut paths = Vec::new();
+        let mut exact = Vec::new();
+        let mut expected = Vec::new();
+        let mut backends = Vec::new();
+        for filter in filters.iter().flat_map(|filter| filter.split_whitespace()) {
+            match filter {
+                _ if filter.starts_with('=') => exact.push(&filter[1..]),
+                "@pass" => expected.push(Expected::Pass),
+                "@fail" => expected.push(Expected::CompileFail),
+                "@run-fail" => expected.push(Expected::RunFail),
+                "@warn" => expected.push(Expected::Warn),
+                "@cranelift" => backends.push(Backend::Cranelift.codegen()),
+                "@llvm" => backends.push(Backend::Llvm.codegen()),
+                path => paths.push(path),
+            }
         }
 
-        tests.retain(|t| filters.iter().any(|f| t.test.path.to_string_lossy().contains(f)));
+        if !backends.is_empty() && !backends.contains(&codegen) {
+            return mem::take(tests);
+        }
+
+        let exact_match = |t: &ExpandedTest, f: &str| {
+            t.name == f || t.test.path == Path::new(f) || t.test.path == resolve(Path::new(f))
+        };
+        let (kept, skipped) = mem::take(tests).into_iter().partition(|t| {
+            (expected.is_empty() || expected.contains(&t.test.expected))
+                && (paths.is_empty() && exact.is_empty()
+                    || paths.iter().any(|f| t.test.path.to_string_lossy().contains(f))
+                    || exact.iter().any(|f| exact_match(t, f)))
+        });
+        *tests = kept;
+        skipped
     }
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-fn glob(pattern: &str) -> Result<Vec<PathBuf>> {
-    let mut paths = glob::glob(pattern)?
-        .map(|entry| entry.map_err(Error::from))
-        .collect::<Result<Vec<PathBuf>>>()?;
-    paths.sort();
+// A relative pattern joined to `root`, whose own `[` or `?` are matched
+// literally rather than taken as part of the pattern.
+fn resolve_pattern(root: Option<&Path>, pattern: &str) -> String {
+    match root.and_then(Path::to_str) {
+        Some(root) if Path::new(pattern).is_relative() => {
+            let root = glob::Pattern::escape(root);
+            Path::new(&root).join(pattern).to_string_lossy().into_owned()
+        }
+        _ => pattern.to_owned(),
+    }
+}
+
+fn glob(pattern: &str, sort: bool) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for pattern in braces::expand(pattern) {
+        for entry in glob::glob(&pattern)? {
+            paths.push(entry?);
+        }
+    }
+    if !sort {
+        return Ok(paths);
+    }
+    // Order the matches the same way on every platform so that the Nth test,
+    // and with it the `trybuildNNN` name, is the same file everywhere.
+    paths.sort_by_cached_key(|path| {
+        let path = path.to_string_lossy().replace('\\', "/");
+        (path.to_lowercase(), path)
+    });
+    paths.dedup();
     Ok(paths)
 }
 
@@ -137,269 +534,2112 @@ fn glob(pattern: &str) -> Result<Vec<PathBuf>> {
 struct Test {
     path: PathBuf,
     expected: Expected,
+    // Error code or lint name that a compile-fail test must report.
+    code: Option<String>,
+    // Build under every backend in one pass and require identical errors.
+    agree: bool,
+    // Compare with LLVM's errors from earlier in the run instead of a
+    // `.stderr` file.
+    reference: bool,
+    // Fail a passing test that prints anything, while building or running.
+    silent: bool,
+    // Backends the test runs under, or all of them if empty.
+    backends: Vec<Backend>,
 }
 
+// Goes between the two streams when `match_stdout` is on. Unlike a bare `---`
+// it doesn't start a new variant of the expected output.
+const STDOUT_SEPARATOR: &str = "--- stdout ---\n";
+
 struct Stderr {
     success: bool,
+    crashed: bool,
     stderr: Vec<u8>,
 }
 
+impl Stderr {
+    fn new(output: Output) -> Self {
+        Stderr {
+            success: output.status.success(),
+            crashed: zxc::crashed(&output),
+            stderr: output.stderr,
+        }
+    }
+}
+
 impl Test {
-    fn run(&self, project: &Project, name: &str, codegen: &str) -> Result<Outcome> {
+    fn run(
+        &self,
+        project: &Project,
+        name: &str,
+        codegen: &str,
+        progress: (usize, usize),
+    ) -> Result<Outcome> {
         let show_expected = project.has_pass && project.has_compile_fail;
-        message::begin_test(self, show_expected);
+        message::begin_test(self, name, codegen, progress, show_expected);
         check_exists(&self.path)?;
+        self.check_expectations(project, codegen)?;
+
+        self.build_and_check(project, name, codegen)
+    }
+
+    // A test whose compiler output doesn't match is rebuilt up to
+    // `project.retries` times. If one of the rebuilds matches, the output is
+    // not deterministic and the test fails as flaky rather than mismatched.
+    fn build_and_check(&self, project: &Project, name: &str, codegen: &str) -> Result<Outcome> {
+        let observe = |output: &Output| {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stderr = if project.json_diagnostics {
+                json::structured(&stderr)
+            } else {
+                stderr.into_owned()
+            };
+            normalize_diagnostics(project, name, &stderr)
+        };
+
+        let output = zxc::build_test(project, &self.path, name, codegen, true)?;
+        let first = observe(&output);
+        let result = self.check_output(project, name, codegen, output);
+
+        let compares_stderr = matches!(self.expected, Expected::CompileFail | Expected::Warn);
+        if !compares_stderr || !matches!(result, Err(Error::Mismatch)) {
+            return result;
+        }
+
+        for attempt in 1..=project.retries {
+            // The cache would only hand back the output that didn't match.
+            let output = zxc::build_test(project, &self.path, name, codegen, false)?;
+            let observed = observe(&output);
+            let retry = term::discard(|| self.check_output(project, name, codegen, output));
+            if let Ok(Outcome::Passed) = retry {
+                message::flaky(attempt, &first, &observed, project.diff);
+                return Err(Error::Flaky(self.path.clone()));
+            }
+        }
+        result
+    }
 
-        let output = zxc::build_test(project, &self.path, name, codegen)?;
-        let stderr = Stderr { success: false, stderr: output.stderr };
-        self.check(project, name, &stderr, &String::from_utf8_lossy(&output.stdout))
+    fn check_output(
+        &self,
+        project: &Project,
+        name: &str,
+        codegen: &str,
+        output: Output,
+    ) -> Result<Outcome> {
+        let build_stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        self.check(project, name, codegen, &Stderr::new(output), &build_stdout)
     }
 
     fn check(
         &self,
         project: &Project,
         name: &str,
+        codegen: &str,
         result: &Stderr,
         build_stdout: &str,
     ) -> Result<Outcome> {
+        // A crashing driver fails the build too, but that is never the failure
+        // a compile-fail test is asking for.
+        if result.crashed && self.expected == Expected::CompileFail {
+            message::driver_ice(&String::from_utf8_lossy(&result.stderr));
+            return Err(Error::DriverIce(self.path.clone()));
+        }
+
         let check = match self.expected {
             Expected::Pass => Test::check_pass,
             Expected::CompileFail => Test::check_compile_fail,
+            Expected::RunFail => Test::check_run_fail,
+            Expected::Warn => Test::check_warn,
         };
 
-        check(
-            self,
-            project,
-            name,
-            result.success,
-            build_stdout,
-            &String::from_utf8_lossy(&result.stderr),
-        )
+        if self.agree && !result.success {
+            self.check_backends_agree(project, name, codegen, &result.stderr)?;
+        }
+
+        let (mut variations, escaped) = normalize::escape_invalid_utf8(&result.stderr);
+        if escaped {
+            message::escaped_invalid_utf8();
+        }
+        let compares_stderr = matches!(self.expected, Expected::CompileFail | Expected::Warn);
+        if project.json_diagnostics {
+            variations = if compares_stderr {
+                json::structured(&variations)
+            } else {
+                json::rendered(&variations)
+            };
+        }
+        if project.match_stdout && compares_stderr && !build_stdout.is_empty() {
+            variations += STDOUT_SEPARATOR;
+            variations += build_stdout;
+        }
+
+        check(self, project, name, codegen, result.success, build_stdout, &variations)
     }
 
     fn check_pass(
         &self,
         project: &Project,
         name: &str,
+        codegen: &str,
         success: bool,
         build_stdout: &str,
         variations: &str,
     ) -> Result<Outcome> {
         if !success {
-            message::failed_to_build(variations);
+            let command = zxc::command_line(project, &self.path, name, codegen).ok();
+            message::failed_to_build(variations, command.as_deref());
+            if project.update == Update::Wip {
+                // Kept for inspection, as when a backend regression breaks the
+                // build of a test that used to pass.
+                let file_name = self
+                    .path
+                    .with_extension(format!("{codegen}.build.stderr"))
+                    .file_name()
+                    .map_or_else(|| OsString::from("test.build.stderr"), OsStr::to_owned);
+                let wip_path = create_wip_dir()?.join(file_name);
+                fs::write(&wip_path, variations).map_err(Error::WriteStderr)?;
+                message::saved_build_output(&wip_path);
+            }
             return Err(Error::CargoFail);
         }
 
-        let mut output = zxc::run_test(project, name)?;
+        self.check_warning_count(project, &normalize_diagnostics(project, name, variations))?;
+
+        let exit_code = self.exit_code()?;
+        let args = self.run_args(project)?;
+        let mut output = zxc::run_test(project, &self.path, name, codegen, &args)?;
+        let success = match exit_code {
+            Some(code) => output.status.code() == Some(code),
+            None => output.status.success(),
+        };
+        let printed = !build_stdout.is_empty()
+            || !variations.is_empty()
+            || !output.stdout.is_empty()
+            || !output.stderr.is_empty();
+        if success && self.silent && printed {
+            output.stdout.splice(..0, build_stdout.bytes());
+            message::not_silent(variations, &output);
+            return Err(Error::NotSilent);
+        }
+        if success {
+            let stdout = String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n");
+            if let Some(outcome) = self.check_stdout(project, &stdout)? {
+                return Ok(outcome);
+            }
+        }
+
         output.stdout.splice(..0, build_stdout.bytes());
-        message::output(variations, &output);
-        if output.status.success() { Ok(Outcome::Passed) } else { Err(Error::RunFailed) }
+        match exit_code {
+            Some(code) if !success => message::wrong_exit_code(variations, code, &output),
+            _ => message::output(variations, &output, success),
+        }
+        if success {
+            Ok(Outcome::Passed)
+        } else {
+            Err(Error::RunFailed)
+        }
     }
 
-    fn check_compile_fail(
+    // The number of warnings the test must produce, if one was given to
+    // `warning_count` or is in a `foo.warnings` file.
+    fn warning_count(&self, project: &Project) -> Result<Option<usize>> {
+        let registered = project.warning_counts.iter().rev().find(|(path, _)| *path == self.path);
+        if let Some(&(_, count)) = registered {
+            return Ok(Some(count));
+        }
+        let warnings_path = self.path.with_extension("warnings");
+        let contents = match fs::read_to_string(&warnings_path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(Error::Open(warnings_path, err)),
+        };
+        match contents.trim().parse() {
+            Ok(count) => Ok(Some(count)),
+            Err(_) => Err(Error::WarningsFile(warnings_path, contents.trim().to_owned())),
+        }
+    }
+
+    fn check_warning_count(&self, project: &Project, normalized: &str) -> Result<()> {
+        let Some(expected) = self.warning_count(project)? else {
+            return Ok(());
+        };
+
+        let actual = count_warnings(normalized);
+        if actual != expected {
+            message::wrong_warning_count(expected, actual, normalized);
+            return Err(Error::WarningCount(expected, actual));
+        }
+        Ok(())
+    }
+
+    // The exit code a passing test must have, from a `foo.exit` file, instead
+    // of just a successful one.
+    fn exit_code(&self) -> Result<Option<i32>> {
+        let exit_path = self.path.with_extension("exit");
+        let contents = match fs::read_to_string(&exit_path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(Error::Open(exit_path, err)),
+        };
+        match contents.trim().parse() {
+            Ok(code) => Ok(Some(code)),
+            Err(_) => Err(Error::ExitFile(exit_path, contents.trim().to_owned())),
+        }
+    }
+
+    // Compares the run output against `foo.stdout`, if there is one. Returns
+    // `None` when the test should be reported the usual way.
+    fn check_stdout(&self, project: &Project, stdout: &str) -> Result<Option<Outcome>> {
+        let stdout_path = self.path.with_extension("stdout");
+
+        if !stdout_path.exists() {
+            if stdout.is_empty() {
+                return Ok(None);
+            }
+            return match project.update {
+                Update::Wip => {
+                    let file_name =
+                        stdout_path.file_name().unwrap_or_else(|| OsStr::new("test.stdout"));
+                    let wip_path = create_wip_dir()?.join(file_name);
+                    message::write_stdout_wip(&wip_path, &stdout_path, stdout);
+                    fs::write(wip_path, stdout).map_err(Error::WriteStdout)?;
+                    Ok(Some(Outcome::CreatedWip))
+                }
+                Update::Overwrite | Update::OverwriteNew => {
+                    message::overwrite_stdout(&stdout_path, stdout);
+                    fs::write(stdout_path, stdout).map_err(Error::WriteStdout)?;
+                    Ok(Some(Outcome::Passed))
+                }
+            };
+        }
+
+        let expected =
+            project.expected_file(&stdout_path).map_err(Error::ReadStdout)?.replace("\r\n", "\n");
+
+        if stdout == expected {
+            return Ok(None);
+        }
+
+        match project.update {
+            Update::Wip | Update::OverwriteNew => {
+                message::stdout_mismatch(&expected, stdout, project.diff);
+                Err(Error::Mismatch)
+            }
+            Update::Overwrite => {
+                message::overwrite_stdout(&stdout_path, stdout);
+                project.overwrite(&stdout_path, stdout.to_owned(), Error::WriteStdout);
+                Ok(Some(Outcome::Passed))
+            }
+        }
+    }
+
+    fn check_run_fail(
         &self,
         project: &Project,
         name: &str,
+        codegen: &str,
         success: bool,
         build_stdout: &str,
         variations: &str,
     ) -> Result<Outcome> {
-        if success {
-            message::should_not_have_compiled();
-            message::fail_output(Fail, build_stdout);
-            message::warnings(variations);
-            return Err(Error::ShouldNotHaveCompiled);
+        if !success {
+            let command = zxc::command_line(project, &self.path, name, codegen).ok();
+            message::failed_to_build(variations, command.as_deref());
+            return Err(Error::CargoFail);
         }
 
-        let stderr_path = self.path.with_extension("stderr");
+        let args = self.run_args(project)?;
+        let mut output = zxc::run_test(project, &self.path, name, codegen, &args)?;
+        if output.status.success() {
+            output.stdout.splice(..0, build_stdout.bytes());
+            message::should_have_failed_at_runtime(variations, &output);
+            return Err(Error::RunSucceeded);
+        }
 
+        let stderr_path = self.stderr_path(codegen);
         if !stderr_path.exists() {
-            let outcome = match project.update {
-                Update::Wip => {
-                    let wip_dir = Path::new("wip");
-                    fs::create_dir_all(wip_dir)?;
-                    let gitignore_path = wip_dir.join(".gitignore");
-                    fs::write(gitignore_path, "*\n")?;
-                    let stderr_name =
-                        stderr_path.file_name().unwrap_or_else(|| OsStr::new("test.stderr"));
-                    let wip_path = wip_dir.join(stderr_name);
-                    message::write_stderr_wip(&wip_path, &stderr_path, variations);
-                    fs::write(wip_path, variations).map_err(Error::WriteStderr)?;
-                    Outcome::CreatedWip
-                }
-                Update::Overwrite => {
-                    message::overwrite_stderr(&stderr_path, variations);
-                    fs::write(stderr_path, variations).map_err(Error::WriteStderr)?;
-                    Outcome::Passed
-                }
-            };
-            message::fail_output(Warn, build_stdout);
-            return Ok(outcome);
+            message::ok();
+            return Ok(Outcome::Passed);
         }
 
+        let context = normalize::Context {
+            project_dir: &project.dir,
+            test_name: name,
+            substitutions: &project.substitutions,
+            ignored_lines: &project.ignored_lines,
+        };
+        let stderr = normalize::diagnostics(&String::from_utf8_lossy(&output.stderr), &context);
         let expected =
-            fs::read_to_string(&stderr_path).map_err(Error::ReadStderr)?.replace("\r\n", "\n");
-
-        // if variations.any(|stderr| expected == stderr) {
-        //     message::ok();
-        //     return Ok(Outcome::Passed);
-        // }
+            project.expected_file(&stderr_path).map_err(Error::ReadStderr)?.replace("\r\n", "\n");
+        let expected = normalize::drop_ignored_lines(&expected, &project.ignored_lines);
 
-        if variations == expected {
+        if stderr == expected {
             message::ok();
             return Ok(Outcome::Passed);
         }
 
         match project.update {
-            Update::Wip => {
-                message::mismatch(&expected, variations);
+            Update::Wip | Update::OverwriteNew => {
+                message::mismatch(&expected, &stderr, project.diff);
                 Err(Error::Mismatch)
             }
             Update::Overwrite => {
-                message::overwrite_stderr(&stderr_path, variations);
-                fs::write(stderr_path, variations).map_err(Error::WriteStderr)?;
+                message::overwrite_stderr(&stderr_path, &stderr);
+                project.overwrite(&stderr_path, stderr, Error::WriteStderr);
                 Ok(Outcome::Passed)
             }
         }
     }
-}
 
-#[derive(Copy, Clone, Debug)]
-enum Expected {
-    Pass,
-    CompileFail,
-}
+    fn check_compile_fail(
+        &self,
+        project: &Project,
+        name: &str,
+        codegen: &str,
+        success: bool,
+        build_stdout: &str,
+        variations: &str,
+    ) -> Result<Outcome> {
+        let normalized = project.transform_stderr(normalize_diagnostics(project, name, variations));
+        let variations = normalized.as_str();
 
-impl TestCases {
-    #[allow(clippy::new_without_default)]
-    pub fn new() -> Self {
-        TestCases { runner: RefCell::new(Runner { tests: Vec::new() }) }
-    }
+        if success {
+            let stderr_path = self.stderr_path(codegen);
+            message::should_not_have_compiled();
+            if let Ok(expected) = fs::read_to_string(&stderr_path) {
+                message::no_longer_produced(&stderr_path, &expected.replace("\r\n", "\n"));
+            }
+            message::fail_output(Fail, build_stdout);
+            message::warnings(variations);
+            return Err(Error::ShouldNotHaveCompiled);
+        }
 
-    pub fn pass<P: AsRef<Path>>(&self, path: P) {
-        self.runner
-            .borrow_mut()
-            .tests
-            .push(Test { path: path.as_ref().to_owned(), expected: Expected::Pass });
-    }
+        if let Some(code) = &self.code {
+            if !has_error_code(variations, code) {
+                message::missing_error_code(code, variations);
+                return Err(Error::MissingErrorCode(code.clone()));
+            }
+        }
 
-    pub fn compile_fail<P: AsRef<Path>>(&self, path: P) {
-        self.runner
-            .borrow_mut()
-            .tests
-            .push(Test { path: path.as_ref().to_owned(), expected: Expected::CompileFail });
-    }
-}
+        self.check_warning_count(project, variations)?;
 
-impl Drop for TestCases {
-    fn drop(&mut self) {
-        if !thread::panicking() {
-            message::report_codegen("Cranelift");
-            self.runner.borrow_mut().run("cranelift");
-            message::report_codegen("LLVM");
-            self.runner.borrow_mut().run("llvm");
+        if self.reference {
+            return self.check_reference(project, codegen, variations);
         }
-    }
-}
-
-#[derive(Debug)]
-pub struct Project {
-    pub dir: Directory,
-    pub has_pass: bool,
-    update: Update,
-    has_compile_fail: bool,
-    pub keep_going: bool,
-}
 
-struct Report {
-    failures: usize,
-    created_wip: usize,
-}
+        if let Some(snapshot) = &project.snapshot {
+            let path = self.path.strip_prefix(&project.dir).unwrap_or(&self.path);
+            let mut pass = codegen.to_owned();
+            if let Some(opt_level) = &project.opt_level {
+                pass += &format!(" opt-level={opt_level}");
+            }
+            let key = (path.to_string_lossy().into_owned(), pass);
+            let mut snapshot = snapshot.lock().unwrap_or_else(PoisonError::into_inner);
+            snapshot.insert(key, variations.to_owned());
+            message::ok();
+            return Ok(Outcome::Passed);
+        }
 
-enum Outcome {
-    Passed,
-    CreatedWip,
-}
+        let contains_path = self.path.with_extension("stderr.contains");
+        if !self.stderr_path(codegen).exists() && contains_path.exists() {
+            return self.check_contains(project, &contains_path, build_stdout, variations);
+        }
 
-fn check_exists(path: &Path) -> Result<()> {
-    if path.exists() {
-        return Ok(());
-    }
-    match File::open(path) {
-        Ok(_) => Ok(()),
-        Err(err) => Err(Error::Open(path.to_owned(), err)),
+        self.check_stderr(project, codegen, build_stdout, variations)
     }
-}
 
-impl Runner {
-    fn prepare(&self, tests: &[ExpandedTest]) -> Result<Project> {
+    // Under LLVM the errors are kept for the other backends, which run later
+    // and must report the same ones.
+    fn check_reference(
+        &self,
+        project: &Project,
+        codegen: &str,
+        variations: &str,
+    ) -> Result<Outcome> {
+        let key = (self.path.clone(), project.opt_level.clone());
+        let mut references = project.references.lock().unwrap_or_else(PoisonError::into_inner);
+        let llvm = Backend::Llvm.codegen();
+        if codegen == llvm {
+            references.insert(key, variations.to_owned());
+            message::ok();
+            return Ok(Outcome::Passed);
+        }
+
+        let Some(reference) = references.get(&key) else {
+            return Err(Error::NoReference(self.path.clone()));
+        };
+        if reference == variations {
+            message::ok();
+            return Ok(Outcome::Passed);
+        }
+        message::backends_disagree((llvm, reference), (codegen, variations), project.diff);
+        Err(Error::BackendsDisagree(self.path.clone()))
+    }
+
+    // The looser match of a `.stderr.contains` file, which only has to appear
+    // somewhere in the output. It is never overwritten.
+    fn check_contains(
+        &self,
+        project: &Project,
+        contains_path: &Path,
+        build_stdout: &str,
+        variations: &str,
+    ) -> Result<Outcome> {
+        let expected =
+            fs::read_to_string(contains_path).map_err(Error::ReadStderr)?.replace("\r\n", "\n");
+        let expected = project.expected_stderr(&expected);
+        let expected = expected.trim_end_matches('\n');
+
+        if variations.contains(expected) {
+            message::ok();
+            return Ok(Outcome::Passed);
+        }
+
+        message::contains_mismatch(contains_path, expected, variations);
+        message::fail_output(Fail, build_stdout);
+        Err(Error::Mismatch)
+    }
+
+    // Builds the test under the other backends and compares their errors with
+    // the ones from `codegen`.
+    fn check_backends_agree(
+        &self,
+        project: &Project,
+        name: &str,
+        codegen: &str,
+        stderr: &[u8],
+    ) -> Result<()> {
+        let (stderr, _) = normalize::escape_invalid_utf8(stderr);
+        let normalized = normalize_diagnostics(project, name, &stderr);
+        for other in Backend::ALL.iter().map(|backend| backend.codegen()) {
+            if other == codegen {
+                continue;
+            }
+            let output = zxc::build_test(project, &self.path, name, other, true)?;
+            let (other_stderr, _) = normalize::escape_invalid_utf8(&output.stderr);
+            let other_normalized = normalize_diagnostics(project, name, &other_stderr);
+            if output.status.success() || other_normalized != normalized {
+                message::backends_disagree(
+                    (codegen, &normalized),
+                    (other, &other_normalized),
+                    project.diff,
+                );
+                return Err(Error::BackendsDisagree(self.path.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_warn(
+        &self,
+        project: &Project,
+        name: &str,
+        codegen: &str,
+        success: bool,
+        build_stdout: &str,
+        variations: &str,
+    ) -> Result<Outcome> {
+        if !success {
+            let command = zxc::command_line(project, &self.path, name, codegen).ok();
+            message::failed_to_build(variations, command.as_deref());
+            return Err(Error::CargoFail);
+        }
+
+        let normalized = project.transform_stderr(normalize_diagnostics(project, name, variations));
+        self.check_warning_count(project, &normalized)?;
+        self.check_stderr(project, codegen, build_stdout, &normalized)
+    }
+
+    // Compares normalized compiler output against the expected `.stderr` file,
+    // creating or overwriting it according to `TRYBUILD`.
+    fn check_stderr(
+        &self,
+        project: &Project,
+        codegen: &str,
+        build_stdout: &str,
+        variations: &str,
+    ) -> Result<Outcome> {
+        if let Some(outcome) = self.check_inline(project, variations)? {
+            return Ok(outcome);
+        }
+
+        let stderr_path = self.stderr_path(codegen);
+
+        if !stderr_path.exists() {
+            let outcome = match project.update {
+                Update::Wip => {
+                    let wip_dir = create_wip_dir()?;
+                    let stderr_name = self
+                        .path
+                        .with_extension(format!("{codegen}.stderr"))
+                        .file_name()
+                        .map_or_else(|| OsString::from("test.stderr"), OsStr::to_owned);
+                    let wip_path = wip_dir.join(stderr_name);
+                    message::write_stderr_wip(&wip_path, &stderr_path, variations);
+                    fs::write(wip_path, variations).map_err(Error::WriteStderr)?;
+                    Outcome::CreatedWip
+                }
+                Update::Overwrite | Update::OverwriteNew => {
+                    message::overwrite_stderr(&stderr_path, variations);
+                    fs::write(stderr_path, variations).map_err(Error::WriteStderr)?;
+                    Outcome::Passed
+                }
+            };
+            message::fail_output(Warn, build_stdout);
+            return Ok(outcome);
+        }
+
+        let expected =
+            project.expected_file(&stderr_path).map_err(Error::ReadStderr)?.replace("\r\n", "\n");
+        let expected = project.expected_stderr(&expected);
+
+        let expected = Variants::parse(&expected);
+
+        if matches_expected(project, &expected, variations) {
+            message::ok();
+            return Ok(Outcome::Passed);
+        }
+
+        let closest = expected.closest(variations);
+        match project.update {
+            Update::Wip | Update::OverwriteNew
+                if expected.any(|expected| normalize::whitespace_eq(expected, variations)) =>
+            {
+                message::whitespace_only_mismatch(&stderr_path);
+                Err(Error::Mismatch)
+            }
+            Update::Wip | Update::OverwriteNew if project.unordered_stderr => {
+                let difference = unordered::compare(expected.get(closest), variations);
+                message::unordered_mismatch(&difference);
+                Err(Error::Mismatch)
+            }
+            Update::Wip | Update::OverwriteNew => {
+                message::mismatch(expected.get(closest), variations, project.diff);
+                Err(Error::Mismatch)
+            }
+            Update::Overwrite => {
+                message::overwrite_stderr(&stderr_path, variations);
+                let contents = expected.replace(closest, variations);
+                project.overwrite(&stderr_path, contents, Error::WriteStderr);
+                Ok(Outcome::Passed)
+            }
+        }
+    }
+
+    // Compares against an `// EXPECTED:` block in the test, which takes the
+    // place of the `.stderr` file. Returns `None` when there is no such block.
+    fn check_inline(&self, project: &Project, variations: &str) -> Result<Option<Outcome>> {
+        let Ok(source) = project.expected_file(&self.path) else {
+            return Ok(None);
+        };
+        let Some(expected) = inline::expected(&source) else {
+            return Ok(None);
+        };
+        let expected = project.expected_stderr(&expected);
+
+        let expected = Variants::parse(&expected);
+        if matches_expected(project, &expected, variations) {
+            message::ok();
+            return Ok(Some(Outcome::Passed));
+        }
+
+        let closest = expected.closest(variations);
+        match project.update {
+            Update::Wip | Update::OverwriteNew => {
+                message::mismatch(expected.get(closest), variations, project.diff);
+                Err(Error::Mismatch)
+            }
+            Update::Overwrite => {
+                message::overwrite_stderr(&self.path, variations);
+                let contents = inline::replace(&source, &expected.replace(closest, variations));
+                project.overwrite(&self.path, contents, Error::WriteStderr);
+                Ok(Some(Outcome::Passed))
+            }
+        }
+    }
+}
+
+// Counts the warnings themselves, leaving out the `warning: 2 warnings
+// emitted` line that sums them up. In structured diagnostics the summary is a
+// warning of its own, told apart by its message.
+fn count_warnings(stderr: &str) -> usize {
+    let lines = stderr.lines().collect::<Vec<_>>();
+    let is_summary = |rest: &[&str]| {
+        rest.iter()
+            .map(|line| line.trim())
+            .find(|line| line.starts_with("\"message\":"))
+            .is_some_and(|message| message.ends_with(" emitted\","))
+    };
+    (0..lines.len())
+        .filter(|&i| {
+            let line = lines[i];
+            line.starts_with("warning[")
+                || line.starts_with("warning:") && !line.ends_with(" emitted")
+                || line.trim() == "\"level\": \"warning\"," && !is_summary(&lines[i + 1..])
+        })
+        .count()
+}
+
+// Looks for `error[E0308]`, or `"code": "E0308"` in structured diagnostics,
+// for error codes, or the `#[deny(...)]` note that comes with an error raised
+// by a lint.
+fn has_error_code(stderr: &str, code: &str) -> bool {
+    stderr.contains(&format!("error[{code}]"))
+        || stderr.contains(&format!("\"code\": \"{code}\""))
+        || stderr.contains(&format!("#[deny({code})]"))
+        || stderr.contains(&format!("#[forbid({code})]"))
+}
+
+// With `unordered_stderr`, diagnostics reported in a different order still
+// match.
+fn matches_expected(project: &Project, expected: &Variants, actual: &str) -> bool {
+    expected.matches(actual)
+        || project.unordered_stderr
+            && expected.any(|expected| unordered::compare(expected, actual).is_empty())
+}
+
+fn normalize_diagnostics(project: &Project, name: &str, variations: &str) -> String {
+    let context = normalize::Context {
+        project_dir: &project.dir,
+        test_name: name,
+        substitutions: &project.substitutions,
+        ignored_lines: &project.ignored_lines,
+    };
+    normalize::diagnostics(variations, &context)
+}
+
+impl Test {
+    // Rejects expected output that would never be compared, such as a warning
+    // count for a run-fail test, or that competes with another source of
+    // expected output for the same test.
+    fn check_expectations(&self, project: &Project, codegen: &str) -> Result<()> {
+        if self.expected == Expected::RunFail && self.warning_count(project)?.is_some() {
+            return Err(Error::WarningCountUnsupported(self.path.clone()));
+        }
+        let stderr_path = self.stderr_path(codegen);
+        if !stderr_path.exists() {
+            return Ok(());
+        }
+        match self.expected {
+            Expected::Pass => Err(Error::UnusedStderr(stderr_path)),
+            Expected::CompileFail | Expected::Warn => {
+                let source = fs::read_to_string(&self.path).unwrap_or_default();
+                match inline::expected(&source) {
+                    Some(_) => Err(Error::InlineAndStderr(self.path.clone(), stderr_path)),
+                    None => Ok(()),
+                }
+            }
+            Expected::RunFail => Ok(()),
+        }
+    }
+
+    // Arguments for the compiled test: those given to `run_args`, or else the
+    // lines of a `.args` file next to the test.
+    fn run_args(&self, project: &Project) -> Result<Vec<OsString>> {
+        if let Some((_, args)) = project.run_args.iter().rev().find(|(path, _)| *path == self.path)
+        {
+            return Ok(args.clone());
+        }
+        let args_path = self.path.with_extension("args");
+        match fs::read_to_string(&args_path) {
+            Ok(contents) => Ok(contents.lines().map(OsString::from).collect()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(Error::Open(args_path, err)),
+        }
+    }
+
+    // Prefer a backend-specific `foo.<codegen>.stderr` over the shared
+    // `foo.stderr` so that divergent diagnostics can be recorded per backend.
+    fn stderr_path(&self, codegen: &str) -> PathBuf {
+        let specific = self.path.with_extension(format!("{codegen}.stderr"));
+        if specific.exists() {
+            specific
+        } else {
+            self.path.with_extension("stderr")
+        }
+    }
+}
+
+/// What a registered test is expected to do.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Expected {
+    Pass,
+    CompileFail,
+    RunFail,
+    Warn,
+}
+
+impl TestCases {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        TestCases {
+            runner: RefCell::new(Runner {
+                tests: Vec::new(),
+                jobs: 1,
+                driver: None,
+                timeout: Some(Duration::from_secs(60)),
+                allowed_slow: Vec::new(),
+                run_args: Vec::new(),
+                warning_counts: Vec::new(),
+                substitutions: Vec::new(),
+                ignored_lines: Vec::new(),
+                report_path: None,
+                junit_path: None,
+                records: Vec::new(),
+                fail_fast: false,
+                quiet: false,
+                diff: DiffLimits::default(),
+                clean: false,
+                done: false,
+                driver_args: Vec::new(),
+                driver_env: Vec::new(),
+                cache: false,
+                prelude: None,
+                keep_artifacts_named: false,
+                out_dir: None,
+                match_stdout: false,
+                dry_run: false,
+                check_orphaned_stderr: false,
+                externs: Vec::new(),
+                opt_levels: Vec::new(),
+                edition: "2021".to_owned(),
+                retries: 0,
+                preserve_order: false,
+                name_prefix: None,
+                json_diagnostics: false,
+                before_all: None,
+                after_all: None,
+                hooks_every_pass: false,
+                excludes: Vec::new(),
+                report_excluded: false,
+                unordered_stderr: false,
+                references: References::default(),
+                transform_stderr: None,
+                snapshot_path: None,
+                snapshot: Snapshot::default(),
+                overwrites: Arc::default(),
+            }),
+        }
+    }
+
+    pub fn pass<P: AsRef<Path>>(&self, path: P) {
+        self.runner.borrow_mut().tests.push(Test {
+            path: path.as_ref().to_owned(),
+            expected: Expected::Pass,
+            code: None,
+            agree: false,
+            reference: false,
+            silent: false,
+            backends: Vec::new(),
+        });
+    }
+
+    /// Like `pass`, but the test also fails if building it prints any
+    /// warnings or other output, or if running it prints anything.
+    pub fn pass_silent<P: AsRef<Path>>(&self, path: P) {
+        self.runner.borrow_mut().tests.push(Test {
+            path: path.as_ref().to_owned(),
+            expected: Expected::Pass,
+            code: None,
+            agree: false,
+            reference: false,
+            silent: true,
+            backends: Vec::new(),
+        });
+    }
+
+    /// Without a `.stderr` file, a `.stderr.contains` file next to the test
+    /// makes it pass as long as the error output contains its text.
+    pub fn compile_fail<P: AsRef<Path>>(&self, path: P) {
+        self.runner.borrow_mut().tests.push(Test {
+            path: path.as_ref().to_owned(),
+            expected: Expected::CompileFail,
+            code: None,
+            agree: false,
+            reference: false,
+            silent: false,
+            backends: Vec::new(),
+        });
+    }
+
+    /// Like `compile_fail`, but additionally requires the error output to
+    /// contain the error code `code`, such as `E0308`, or the name of the lint
+    /// that rejected the test, whether or not the rest of it matches the
+    /// `.stderr` file.
+    pub fn compile_fail_with_code<P: AsRef<Path>>(&self, path: P, code: &str) {
+        self.runner.borrow_mut().tests.push(Test {
+            path: path.as_ref().to_owned(),
+            expected: Expected::CompileFail,
+            code: Some(code.to_owned()),
+            agree: false,
+            reference: false,
+            silent: false,
+            backends: Vec::new(),
+        });
+    }
+
+    /// Like `compile_fail`, but the test is built under both backends in the
+    /// same pass, and fails unless they report identical errors.
+    pub fn compile_fail_backends_agree<P: AsRef<Path>>(&self, path: P) {
+        self.runner.borrow_mut().tests.push(Test {
+            path: path.as_ref().to_owned(),
+            expected: Expected::CompileFail,
+            code: None,
+            agree: true,
+            reference: false,
+            silent: false,
+            backends: Vec::new(),
+        });
+    }
+
+    /// Like `compile_fail`, but instead of a `.stderr` file the errors LLVM
+    /// reported for the test earlier in the same run are expected from the
+    /// other backends. LLVM runs first whenever such a test is registered.
+    pub fn compile_fail_match_reference<P: AsRef<Path>>(&self, path: P) {
+        self.runner.borrow_mut().tests.push(Test {
+            path: path.as_ref().to_owned(),
+            expected: Expected::CompileFail,
+            code: None,
+            agree: false,
+            reference: true,
+            silent: false,
+            backends: Vec::new(),
+        });
+    }
+
+    pub fn run_fail<P: AsRef<Path>>(&self, path: P) {
+        self.runner.borrow_mut().tests.push(Test {
+            path: path.as_ref().to_owned(),
+            expected: Expected::RunFail,
+            code: None,
+            agree: false,
+            reference: false,
+            silent: false,
+            backends: Vec::new(),
+        });
+    }
+
+    /// Like `pass`, but the test is only compiled, and the warnings it emits
+    /// must match its `.stderr` file.
+    pub fn warn<P: AsRef<Path>>(&self, path: P) {
+        self.runner.borrow_mut().tests.push(Test {
+            path: path.as_ref().to_owned(),
+            expected: Expected::Warn,
+            code: None,
+            agree: false,
+            reference: false,
+            silent: false,
+            backends: Vec::new(),
+        });
+    }
+
+    /// Registers every test listed in a TOML file, each as a `[[test]]` table
+    /// with a `path` relative to the file, what to `expect` of it (`pass`,
+    /// `compile-fail`, `run-fail` or `warn`), and optionally the error `code`
+    /// of a compile-fail test and the `backends` it is limited to.
+    pub fn from_manifest<P: AsRef<Path>>(&self, path: P) {
+        let path = path.as_ref();
+        let file = match env::manifest_dir() {
+            Some(root) if path.is_relative() => root.join(path),
+            _ => path.to_owned(),
+        };
+        let entries = manifest::load(&file).unwrap_or_else(|err| {
+            message::prepare_fail(err);
+            panic!("tests failed");
+        });
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut runner = self.runner.borrow_mut();
+        for entry in entries {
+            runner.tests.push(Test {
+                path: dir.join(&entry.path),
+                expected: entry.expected(),
+                code: entry.code,
+                agree: false,
+                reference: false,
+                silent: false,
+                backends: entry.backends,
+            });
+        }
+    }
+
+    pub fn jobs(&self, jobs: usize) {
+        self.runner.borrow_mut().jobs = jobs;
+    }
+
+    /// Use a prebuilt driver binary instead of building the `driver` package.
+    ///
+    /// The driver is resolved in this order: the path given here, then the
+    /// `TRYBUILD_DRIVER` environment variable, and finally the default of
+    /// running `cargo build --package driver` and executing
+    /// `../target/debug/driver`. If `TRYBUILD_DRIVER_WRAPPER` is set, that
+    /// program is run instead, with the driver as its first argument. A
+    /// driver whose sysroot is not in the default location, as when cross
+    /// compiling, is given the one in `TRYBUILD_SYSROOT` with `--sysroot`.
+    pub fn driver<P: AsRef<Path>>(&self, path: P) {
+        self.runner.borrow_mut().driver = Some(path.as_ref().to_owned());
+    }
+
+    /// Kill a compiled test that runs for longer than `timeout`. Defaults to 60
+    /// seconds.
+    pub fn timeout(&self, timeout: Duration) {
+        self.runner.borrow_mut().timeout = Some(timeout);
+    }
+
+    /// Exempt the test at `path` from the `timeout` and leave it out of the
+    /// slowest tests listed by `TRYBUILD_TIMINGS`, for tests known to be
+    /// expensive. A `.slow` file next to a test does the same.
+    pub fn allow_slow<P: AsRef<Path>>(&self, path: P) {
+        self.runner.borrow_mut().allowed_slow.push(path.as_ref().to_owned());
+    }
+
+    /// Require the test at `path` to produce exactly `count` warnings, however
+    /// they are worded. A `.warnings` file next to a test holding just the
+    /// number does the same. Run-fail tests don't support a warning count.
+    pub fn warning_count<P: AsRef<Path>>(&self, path: P, count: usize) {
+        self.runner.borrow_mut().warning_counts.push((path.as_ref().to_owned(), count));
+    }
+
+    /// Pass `args` to the compiled test at `path` when running it. Without
+    /// this, the lines of a `.args` file next to the test are passed, one
+    /// argument per line and without any shell quoting.
+    pub fn run_args<P, I, S>(&self, path: P, args: I)
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let args = args.into_iter().map(|arg| arg.as_ref().to_owned()).collect();
+        self.runner.borrow_mut().run_args.push((path.as_ref().to_owned(), args));
+    }
+
+    /// Replace `pattern` with `replacement` in compiler output before it is
+    /// compared. Rules run in the order they were added, after the built-in
+    /// normalization of the project directory, so they see `$DIR` rather than
+    /// absolute paths. The pattern is literal apart from the `{HASH}` (16 hex
+    /// digits) and `{NUM}` (a run of digits) placeholders.
+    pub fn normalize(&self, pattern: &str, replacement: &str) {
+        self.runner.borrow_mut().substitutions.push(Substitution::new(pattern, replacement));
+    }
+
+    /// Leave out every line containing `pattern` from both the expected and
+    /// the actual compiler output before they are compared, for lines such as
+    /// timings that change from run to run. The pattern is matched after
+    /// normalization and takes the same placeholders as in `normalize`.
+    pub fn ignore_line(&self, pattern: &str) {
+        self.runner.borrow_mut().ignored_lines.push(Substitution::new(pattern, ""));
+    }
+
+    /// Write a JSON summary of every test result, across all backends, to
+    /// `path`. Setting `TRYBUILD_REPORT=json` does the same with a default
+    /// path of `report.json` in the output directory.
+    pub fn report_json<P: AsRef<Path>>(&self, path: P) {
+        self.runner.borrow_mut().report_path = Some(path.as_ref().to_owned());
+    }
+
+    /// Compare the errors of all compile-fail tests with the single file at
+    /// `path` instead of a `.stderr` file per test. The file holds the output
+    /// of every test under every backend, each after a `==> path [backend]`
+    /// header, and is checked and overwritten as a whole after the last test
+    /// has run.
+    pub fn snapshot<P: AsRef<Path>>(&self, path: P) {
+        self.runner.borrow_mut().snapshot_path = Some(path.as_ref().to_owned());
+    }
+
+    /// Write the results as JUnit XML to `path`, with the backend of each
+    /// result in its class name. Failures carry the error and the output
+    /// printed for the test.
+    pub fn junit<P: AsRef<Path>>(&self, path: P) {
+        self.runner.borrow_mut().junit_path = Some(path.as_ref().to_owned());
+    }
+
+    /// Stop at the first failing test instead of running the rest of the
+    /// suite.
+    pub fn fail_fast(&self, fail_fast: bool) {
+        self.runner.borrow_mut().fail_fast = fail_fast;
+    }
+
+    /// Print only the tests that did not pass, followed by the summary. Also
+    /// enabled by `TRYBUILD_QUIET`.
+    pub fn quiet(&self, quiet: bool) {
+        self.runner.borrow_mut().quiet = quiet;
+    }
+
+    /// Skip highlighting differences when expected and actual output together
+    /// are longer than `len` bytes. Defaults to 2048.
+    pub fn diff_limit(&self, len: usize) {
+        self.runner.borrow_mut().diff.max_len = len;
+    }
+
+    /// Only highlight differences when at least this fraction of the longer
+    /// output is shared with the other. Defaults to 0.8.
+    pub fn diff_similarity(&self, fraction: f64) {
+        self.runner.borrow_mut().diff.min_similarity = fraction;
+    }
+
+    /// Delete previously built test binaries before running, so that renamed
+    /// or removed tests don't leave stale artifacts behind. This empties the
+    /// whole output directory, so with a directory given to `out_dir` it only
+    /// works if trybuild created that directory itself; cleaning one that
+    /// already existed is an error rather than a deletion of its contents.
+    pub fn clean(&self) {
+        self.runner.borrow_mut().clean = true;
+    }
+
+    pub fn driver_arg<S: Into<OsString>>(&self, arg: S) {
+        self.runner.borrow_mut().driver_args.push(arg.into());
+    }
+
+    pub fn driver_args<I, S>(&self, args: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.runner.borrow_mut().driver_args.extend(args.into_iter().map(Into::into));
+    }
+
+    /// Set an environment variable for every invocation of the driver. A
+    /// `foo.env` file next to `foo.rs` with `KEY=VALUE` lines can override it
+    /// for that test. Neither applies when running the compiled test.
+    pub fn env<K: Into<OsString>, V: Into<OsString>>(&self, key: K, value: V) {
+        self.runner.borrow_mut().driver_env.push((key.into(), value.into()));
+    }
+
+    /// Reuse the result of a previous build of a test when neither its source,
+    /// the driver binary, nor the driver arguments have changed since. Cached
+    /// results live in `cache` under the output directory.
+    pub fn cache(&self, cache: bool) {
+        self.runner.borrow_mut().cache = cache;
+    }
+
+    /// Code to add to every test, such as `#![feature(...)]` attributes and
+    /// shared imports. Leading inner attributes are passed to the driver as
+    /// `-Zcrate-attr`, and the rest is appended after the test's last line,
+    /// so the test's own inner attributes stay first and line and column
+    /// numbers in diagnostics are unchanged.
+    pub fn prelude(&self, code: &str) {
+        self.runner.borrow_mut().prelude = Some(code.to_owned());
+    }
+
+    /// Name each compiled test after its source path, e.g. `tests_ui_foo` for
+    /// `tests/ui/foo.rs`, instead of `trybuildNNN`, so that the binaries in
+    /// output directory can be found again and rerun by hand.
+    pub fn keep_artifacts_named(&self, named: bool) {
+        self.runner.borrow_mut().keep_artifacts_named = named;
+    }
+
+    /// Where compiled tests and other build output go. Defaults to
+    /// `trybuild/<crate>` in the Cargo target directory, so that test binaries
+    /// of different crates never share one.
+    pub fn out_dir<P: AsRef<Path>>(&self, dir: P) {
+        self.runner.borrow_mut().out_dir = Some(dir.as_ref().to_owned());
+    }
+
+    /// Compare what the driver prints to stdout as well, appended to the
+    /// `.stderr` file after a `--- stdout ---` line.
+    pub fn match_stdout(&self, yes: bool) {
+        self.runner.borrow_mut().match_stdout = yes;
+    }
+
+    /// List the tests that would run, under each backend, without building
+    /// any of them. `TRYBUILD_DRY_RUN=1` does the same.
+    pub fn dry_run(&self, yes: bool) {
+        self.runner.borrow_mut().dry_run = yes;
+    }
+
+    /// Fail for every `.stderr` file next to the registered tests that none of
+    /// them would compare against, such as one left behind by a deleted test.
+    pub fn check_orphaned_stderr(&self, yes: bool) {
+        self.runner.borrow_mut().check_orphaned_stderr = yes;
+    }
+
+    /// Make the crate at `rlib` available to the tests as `name`, through
+    /// `--extern`. A `{backend}` in the path is replaced with the backend
+    /// being tested, for dependencies built separately for each one.
+    pub fn extern_crate<P: AsRef<Path>>(&self, name: &str, rlib: P) {
+        self.runner.borrow_mut().externs.push((name.to_owned(), rlib.as_ref().to_owned()));
+    }
+
+    /// Build the tests with `-C opt-level=<level>`. Calling this more than
+    /// once runs the whole suite at each of the levels, under every backend.
+    pub fn opt_level(&self, level: &str) {
+        self.runner.borrow_mut().opt_levels.push(level.to_owned());
+    }
+
+    /// Build the tests as Rust `edition`, which is 2021 unless set here,
+    /// rather than leave it to the driver. A `.edition` file next to a test
+    /// holding just the edition, such as `2015`, overrides it for that test.
+    pub fn edition(&self, edition: &str) {
+        self.runner.borrow_mut().edition = edition.to_owned();
+    }
+
+    /// Run the tests in the order they were registered, with the matches of
+    /// a pattern in the order the file system listed them, instead of in
+    /// sorted order. The `trybuildNNN` names follow the same order.
+    pub fn preserve_order(&self, preserve_order: bool) {
+        self.runner.borrow_mut().preserve_order = preserve_order;
+    }
+
+    /// Name the compiled tests `<prefix>_NNN` instead of `trybuildNNN`, such
+    /// as `mycrate_ui_000`, so that test crates sharing an output directory
+    /// don't overwrite each other's binaries. The prefix should consist of
+    /// letters, digits and underscores.
+    pub fn name_prefix(&self, prefix: &str) {
+        self.runner.borrow_mut().name_prefix = Some(prefix.to_owned());
+    }
+
+    /// Have the driver report diagnostics as JSON with `--error-format json`.
+    /// Compile-fail and warn tests are then compared on the level, code,
+    /// message and spans of each diagnostic, written out as pretty-printed
+    /// JSON, so that their expected output doesn't depend on how the driver
+    /// renders diagnostics.
+    pub fn json_diagnostics(&self, enabled: bool) {
+        self.runner.borrow_mut().json_diagnostics = enabled;
+    }
+
+    /// Accept compiler output that holds the expected diagnostics in a
+    /// different order. Diagnostics are told apart by the blank lines between
+    /// them.
+    pub fn unordered_stderr(&self, unordered: bool) {
+        self.runner.borrow_mut().unordered_stderr = unordered;
+    }
+
+    /// Leave out the tests matching `pattern`, a path or a glob pattern like
+    /// those the tests are registered with.
+    pub fn exclude<P: AsRef<Path>>(&self, pattern: P) {
+        self.runner.borrow_mut().excludes.push(pattern.as_ref().to_owned());
+    }
+
+    /// Count the tests left out by `exclude` as skipped in the results
+    /// instead of dropping them silently.
+    pub fn report_excluded(&self, report: bool) {
+        self.runner.borrow_mut().report_excluded = report;
+    }
+
+    /// Call `f` before the first test is built, once the output directory is
+    /// ready, for example to build fixtures. An error from `f` fails the run
+    /// without building any test.
+    pub fn before_all<F>(&self, f: F)
+    where
+        F: FnMut(&Project) -> Result<(), Box<dyn StdError>> + 'static,
+    {
+        self.runner.borrow_mut().before_all = Some(Hook(Box::new(f)));
+    }
+
+    /// Call `f` after the last test has run, before any failure is reported.
+    pub fn after_all<F>(&self, f: F)
+    where
+        F: FnMut(&Project) + 'static,
+    {
+        self.runner.borrow_mut().after_all = Some(Hook(Box::new(f)));
+    }
+
+    /// Rewrite the diagnostics of compile-fail and warn tests with `f` after
+    /// the built-in normalization, for normalization specific to a project.
+    /// Both the expected and the actual output go through `f` before they are
+    /// compared, and output written under `TRYBUILD=overwrite` is the
+    /// rewritten one. Tests may run on several threads at once with `jobs`,
+    /// hence the `Send + Sync` bound.
+    pub fn transform_stderr<F>(&self, f: F)
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.runner.borrow_mut().transform_stderr = Some(Arc::new(Hook(Box::new(f))));
+    }
+
+    /// Call the `before_all` and `after_all` callbacks around every pass over
+    /// the tests, that is once per backend and opt level, instead of once
+    /// around the whole run.
+    pub fn hooks_every_pass(&self, every_pass: bool) {
+        self.runner.borrow_mut().hooks_every_pass = every_pass;
+    }
+
+    /// Rebuild a compile-fail or warn test whose output doesn't match up to
+    /// `n` more times. If any rebuild matches, the test still fails, but is
+    /// reported as flaky along with both outputs.
+    pub fn retry(&self, n: usize) {
+        self.runner.borrow_mut().retries = n;
+    }
+}
+
+impl TestCases {
+    /// Run the suite under every selected backend and return the combined
+    /// results instead of panicking on failure.
+    ///
+    /// Once this has been called, dropping the `TestCases` does not run the
+    /// tests again.
+    pub fn run_and_collect(&self) -> Report {
+        let mut runner = self.runner.borrow_mut();
+        runner.done = true;
+
+        let mut backends = Backend::env().unwrap_or_else(|err| {
+            message::prepare_fail(err);
+            panic!("tests failed");
+        });
+        if runner.tests.iter().any(|test| test.reference) {
+            backends.sort_by_key(|backend| *backend != Backend::Llvm);
+        }
+
+        // Without any `opt_level`, the driver picks its default.
+        let opt_levels = match runner.opt_levels.as_slice() {
+            [] => vec![None],
+            levels => levels.iter().cloned().map(Some).collect(),
+        };
+
+        let mut report = Report::default();
+        let passes = backends.len() * opt_levels.len();
+        let stop_after_backend_fail = env::stop_after_backend_fail();
+        'backends: for (i, backend) in backends.iter().copied().enumerate() {
+            let start = Instant::now();
+            let failures_before = report.failures;
+            for (j, opt_level) in opt_levels.iter().enumerate() {
+                message::report_codegen(backend.display(), opt_level.as_deref());
+                let pass = i * opt_levels.len() + j;
+                let first_pass = pass == 0;
+                // Whether no other pass will follow this one once it's done.
+                let last_pass = |pass_report: &Report| {
+                    let failed = report.failures + pass_report.failures > failures_before;
+                    let backend_done = j + 1 == opt_levels.len();
+                    pass + 1 == passes || stop_after_backend_fail && backend_done && failed
+                };
+                let codegen = backend.codegen();
+                let pass_report = runner.run(codegen, opt_level.as_deref(), first_pass, &last_pass);
+                report.absorb(pass_report);
+                if report.stopped_at.is_some() {
+                    report.backend_times.push((backend, start.elapsed()));
+                    break 'backends;
+                }
+            }
+            report.backend_times.push((backend, start.elapsed()));
+            if stop_after_backend_fail && report.failures > failures_before {
+                report.not_run = backends[i + 1..].to_vec();
+                break;
+            }
+        }
+
+        runner.check_snapshot(&mut report, &backends);
+        runner.apply_overwrites(&mut report);
+
+        if env::verbose() {
+            if let Ok(dir) = std::env::current_dir() {
+                let out_dir = dir.join(runner.out_dir.clone().unwrap_or_else(env::out_dir));
+                let (bytes, files) = disk_usage(&out_dir);
+                message::disk_usage(&out_dir, bytes, files);
+            }
+        }
+
+        if env::timings() {
+            let allowed_slow =
+                runner.allowed_slow.iter().map(|path| resolve(path)).collect::<Vec<_>>();
+            let records = report
+                .records
+                .iter()
+                .filter(|record| !allows_slow(&allowed_slow, Path::new(&record.path)))
+                .cloned()
+                .collect::<Vec<_>>();
+            message::timings(&report.backend_times, &records);
+        }
+
+        report
+    }
+
+    /// Run the tests like dropping the `TestCases` would, but on failure print
+    /// the reason and exit the process with status 1 instead of panicking.
+    /// This suits a custom `main` better than a panic does.
+    pub fn run_or_exit(&self) {
+        let report = self.run_and_collect();
+        if let Some(failure) = conclude(report) {
+            message::run_failed(&failure);
+            term::flush();
+            process::exit(1);
+        }
+    }
+
+    /// The tests registered so far, with patterns not yet expanded.
+    pub fn tests(&self) -> Vec<(PathBuf, Expected)> {
+        let runner = self.runner.borrow();
+        runner.tests.iter().map(|test| (test.path.clone(), test.expected)).collect()
+    }
+
+    /// The tests that would run: patterns expanded and `trybuild=` filters
+    /// applied.
+    pub fn expanded(&self) -> Vec<(PathBuf, Expected)> {
+        let runner = self.runner.borrow();
+        let backends = Backend::env().unwrap_or_else(|_| Backend::ALL.to_vec());
+        // A test limited to some backends is selected if any of them runs.
+        let mut selected = Vec::new();
+        let mut seen = HashSet::new();
+        for backend in backends {
+            let (mut tests, _) = runner.expand_globs();
+            if let Err(err) = runner.exclude(&mut tests) {
+                message::prepare_fail(err);
+                panic!("tests failed");
+            }
+            if let Err(err) = Runner::shard(&mut tests) {
+                message::prepare_fail(err);
+                panic!("tests failed");
+            }
+            let _ = Runner::restrict(&mut tests, backend.codegen());
+            let _ = Runner::filter(&mut tests, backend.codegen());
+            for t in tests {
+                if seen.insert(t.test.path.clone()) {
+                    selected.push((t.test.path, t.test.expected));
+                }
+            }
+        }
+        selected
+    }
+}
+
+impl Drop for TestCases {
+    fn drop(&mut self) {
+        if !thread::panicking() && !self.runner.borrow().done {
+            let report = self.run_and_collect();
+            if let Some(failure) = conclude(report) {
+                panic!("{}", failure);
+            }
+        }
+    }
+}
+
+// Prints the summary of a finished run and returns why it failed, if it did.
+fn conclude(report: Report) -> Option<String> {
+    let ran = report.total - report.skipped;
+    if report.failures > 0 {
+        message::summary(&report.backend_times, &report.records);
+        let divergences = report::divergences(&report.records);
+        if !divergences.is_empty() {
+            message::backend_divergence(&divergences);
+        }
+    }
+    if report.skipped > 0 {
+        message::tally(ran, report.skipped, report.failures, report.total);
+    }
+    if let Some(path) = report.stopped_at {
+        Some(format!("stopped after the first failure in {} (fail-fast)", path.display()))
+    } else if !report.not_run.is_empty() {
+        let not_run = report.not_run.iter().map(|backend| backend.display()).collect::<Vec<_>>();
+        Some(format!(
+            "{} of {} tests failed; {} was not run",
+            report.failures,
+            ran,
+            not_run.join(" and "),
+        ))
+    } else if report.failures > 0 {
+        Some(format!("{} of {} tests failed", report.failures, ran))
+    } else if report.created_wip > 0 {
+        Some(
+            format!("successfully created new stderr files for {} test cases", report.created_wip,),
+        )
+    } else {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub struct Project {
+    pub dir: Directory,
+    pub has_pass: bool,
+    update: Update,
+    has_compile_fail: bool,
+    pub keep_going: bool,
+    driver: Option<PathBuf>,
+    sysroot: Option<PathBuf>,
+    timeout: Option<Duration>,
+    allowed_slow: Vec<PathBuf>,
+    run_args: Vec<(PathBuf, Vec<OsString>)>,
+    warning_counts: Vec<(PathBuf, usize)>,
+    substitutions: Vec<Substitution>,
+    ignored_lines: Vec<Substitution>,
+    report_path: Option<PathBuf>,
+    junit_path: Option<PathBuf>,
+    diff: DiffLimits,
+    driver_args: Vec<OsString>,
+    driver_env: Vec<(OsString, OsString)>,
+    cache: bool,
+    verbose: bool,
+    quiet: bool,
+    prelude: Option<String>,
+    out_dir: PathBuf,
+    owns_out_dir: bool,
+    match_stdout: bool,
+    externs: Vec<(String, PathBuf)>,
+    opt_level: Option<String>,
+    edition: String,
+    retries: usize,
+    overwrites: Overwrites,
+    json_diagnostics: bool,
+    unordered_stderr: bool,
+    references: References,
+    transform_stderr: Option<Arc<Hook<TransformStderr>>>,
+    snapshot: Option<Snapshot>,
+}
+
+impl Project {
+    /// Where the built tests go, which is also a place for fixtures built by
+    /// a `before_all` callback.
+    pub fn out_dir(&self) -> &Path {
+        &self.out_dir
+    }
+
+    // Queues a rewrite of existing expected output under `TRYBUILD=overwrite`,
+    // to be made by `apply_overwrites` once every test has run.
+    fn overwrite(&self, path: &Path, contents: String, error: fn(io::Error) -> Error) {
+        queue_overwrite(&self.overwrites, path, contents, error);
+    }
+
+    // Expected output as it will be once the queued rewrites are made, so that
+    // a later pass compares against what an earlier one is going to write.
+    fn expected_file(&self, path: &Path) -> io::Result<String> {
+        let overwrites = self.overwrites.lock().unwrap_or_else(PoisonError::into_inner);
+        match overwrites.get(path) {
+            Some((contents, _)) => Ok(contents.clone()),
+            None => fs::read_to_string(path),
+        }
+    }
+
+    // Expected diagnostics get the same treatment as the actual ones after
+    // normalization.
+    fn expected_stderr(&self, expected: &str) -> String {
+        self.transform_stderr(normalize::drop_ignored_lines(expected, &self.ignored_lines))
+    }
+
+    // Applies the user's `transform_stderr`, if any, to expected or actual
+    // diagnostics.
+    fn transform_stderr(&self, stderr: String) -> String {
+        match &self.transform_stderr {
+            Some(transform) => (transform.0)(&stderr),
+            None => stderr,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub failures: usize,
+    pub created_wip: usize,
+    // Tests that were found but not run, which `total` includes.
+    pub skipped: usize,
+    total: usize,
+    records: Vec<Record>,
+    stopped_at: Option<PathBuf>,
+    backend_times: Vec<(Backend, Duration)>,
+    // Backends left out because an earlier one had failures.
+    not_run: Vec<Backend>,
+}
+
+impl Report {
+    fn absorb(&mut self, other: Report) {
+        self.failures += other.failures;
+        self.created_wip += other.created_wip;
+        self.skipped += other.skipped;
+        self.total += other.total;
+        self.records.extend(other.records);
+        self.stopped_at = self.stopped_at.take().or(other.stopped_at);
+        self.backend_times.extend(other.backend_times);
+    }
+
+    fn absorb_skipped(&mut self, skipped: Vec<Record>) {
+        self.skipped += skipped.len();
+        self.total += skipped.len();
+        self.records.extend(skipped);
+    }
+}
+
+enum Outcome {
+    Passed,
+    CreatedWip,
+}
+
+// A test may also be a directory holding a multi-file crate, rooted at its
+// `main.rs` or else its `lib.rs`.
+fn crate_root(dir: &Path) -> Option<PathBuf> {
+    ["main.rs", "lib.rs"].into_iter().map(|root| dir.join(root)).find(|root| root.exists())
+}
+
+// The `wip` directory, kept out of version control, where new expected output
+// is written for review.
+fn create_wip_dir() -> Result<&'static Path> {
+    let wip_dir = Path::new("wip");
+    fs::create_dir_all(wip_dir)?;
+    fs::write(wip_dir.join(".gitignore"), "*\n")?;
+    Ok(wip_dir)
+}
+
+fn queue_overwrite(
+    overwrites: &Overwrites,
+    path: &Path,
+    contents: String,
+    error: fn(io::Error) -> Error,
+) {
+    let mut overwrites = overwrites.lock().unwrap_or_else(PoisonError::into_inner);
+    overwrites.insert(path.to_owned(), (contents, error));
+}
+
+// The output of each test and pass in a snapshot file, the inverse of how
+// `check_snapshot` puts it together.
+fn snapshot_sections(contents: &str) -> BTreeMap<(String, String), String> {
+    let mut sections = BTreeMap::new();
+    let mut current: Option<((String, String), String)> = None;
+    let mut finish = |current: Option<((String, String), String)>| {
+        if let Some((key, mut output)) = current {
+            // Each section is followed by a blank line.
+            if output.ends_with('\n') {
+                output.pop();
+            }
+            sections.insert(key, output);
+        }
+    };
+    for line in contents.split_inclusive('\n') {
+        let header = line
+            .strip_prefix("==> ")
+            .and_then(|header| header.trim_end().strip_suffix(']'))
+            .and_then(|header| header.rsplit_once(" ["));
+        match (header, &mut current) {
+            (Some((test, pass)), _) => {
+                finish(current.take());
+                current = Some(((test.to_owned(), pass.to_owned()), String::new()));
+            }
+            (None, Some((_, output))) => output.push_str(line),
+            (None, None) => {}
+        }
+    }
+    finish(current);
+    sections
+}
+
+fn check_snapshot_file(
+    path: &Path,
+    actual: &str,
+    update: Update,
+    diff: DiffLimits,
+    overwrites: &Overwrites,
+) -> Result<Outcome> {
+    if !path.exists() {
+        return match update {
+            Update::Wip => {
+                let wip_dir = create_wip_dir()?;
+                let file_name = path.file_name().unwrap_or_else(|| OsStr::new("snapshot"));
+                let wip_path = wip_dir.join(file_name);
+                message::write_stderr_wip(&wip_path, path, actual);
+                fs::write(wip_path, actual).map_err(Error::WriteStderr)?;
+                Ok(Outcome::CreatedWip)
+            }
+            Update::Overwrite | Update::OverwriteNew => {
+                message::overwrite_stderr(path, actual);
+                fs::write(path, actual).map_err(Error::WriteStderr)?;
+                Ok(Outcome::Passed)
+            }
+        };
+    }
+
+    let expected = fs::read_to_string(path).map_err(Error::ReadStderr)?.replace("\r\n", "\n");
+    if expected == actual {
+        message::ok();
+        return Ok(Outcome::Passed);
+    }
+    match update {
+        Update::Wip | Update::OverwriteNew => {
+            message::mismatch(&expected, actual, diff);
+            Err(Error::Mismatch)
+        }
+        Update::Overwrite => {
+            message::overwrite_stderr(path, actual);
+            queue_overwrite(overwrites, path, actual.to_owned(), Error::WriteStderr);
+            Ok(Outcome::Passed)
+        }
+    }
+}
+
+// A path given to one of the settings that name a test, resolved like the
+// paths of tests.
+fn resolve(path: &Path) -> PathBuf {
+    match env::manifest_dir() {
+        Some(root) if path.is_relative() => root.join(path),
+        _ => path.to_owned(),
+    }
+}
+
+// Tests registered with `allow_slow`, or with a `.slow` file next to them,
+// aren't held to any time limit.
+fn allows_slow(allowed: &[PathBuf], path: &Path) -> bool {
+    path.with_extension("slow").exists() || allowed.iter().any(|allowed| allowed == path)
+}
+
+// Total size and number of the files under `dir`, which nothing ever cleans
+// up. Symbolic links aren't followed, and whatever can't be read is left out.
+fn disk_usage(dir: &Path) -> (u64, usize) {
+    let (mut bytes, mut files) = (0, 0);
+    let Ok(entries) = fs::read_dir(dir) else {
+        return (bytes, files);
+    };
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.path().symlink_metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            let (dir_bytes, dir_files) = disk_usage(&entry.path());
+            bytes += dir_bytes;
+            files += dir_files;
+        } else {
+            bytes += metadata.len();
+            files += 1;
+        }
+    }
+    (bytes, files)
+}
+
+// Keeps built tests out of version control, like the `wip` directory. Not
+// being able to is no reason to fail the tests.
+// A directory given to `out_dir` may hold files of the user's, so trybuild
+// leaves a marker in the ones it creates and only ever cleans those. Returns
+// whether the marker is there.
+fn create_out_dir(out_dir: &Path) -> Result<bool> {
+    let marker = out_dir.join(".trybuild");
+    if !out_dir.exists() {
+        fs::create_dir_all(out_dir)?;
+        fs::write(&marker, "")?;
+    }
+    Ok(marker.exists())
+}
+
+fn ignore_artifacts(out_dir: &Path) {
+    let gitignore = out_dir.join(".gitignore");
+    if gitignore.exists() {
+        return;
+    }
+    if let Err(err) = fs::create_dir_all(out_dir).and_then(|()| fs::write(&gitignore, "*\n")) {
+        message::gitignore_unavailable(&gitignore, &err);
+    }
+}
+
+fn check_exists(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        return match crate_root(path) {
+            Some(_) => Ok(()),
+            None => {
+                let message = "directory has neither a main.rs nor a lib.rs";
+                Err(Error::Open(path.to_owned(), io::Error::new(io::ErrorKind::NotFound, message)))
+            }
+        };
+    }
+    if path.exists() {
+        return Ok(());
+    }
+    match File::open(path) {
+        Ok(_) => Ok(()),
+        Err(err) => match fs::read_link(path) {
+            // The link itself is there but `exists` couldn't follow it.
+            Ok(target) if err.kind() == io::ErrorKind::NotFound => {
+                let message = format!("broken symlink to {}", target.display());
+                Err(Error::Open(path.to_owned(), io::Error::new(err.kind(), message)))
+            }
+            _ => Err(Error::Open(path.to_owned(), err)),
+        },
+    }
+}
+
+impl Runner {
+    fn prepare(&self, tests: &[ExpandedTest], opt_level: Option<&str>) -> Result<Project> {
         let mut has_pass = false;
         let mut has_compile_fail = false;
         for e in tests {
             match e.test.expected {
-                Expected::Pass => has_pass = true,
-                Expected::CompileFail => has_compile_fail = true,
+                Expected::Pass | Expected::RunFail => has_pass = true,
+                Expected::CompileFail | Expected::Warn => has_compile_fail = true,
             }
         }
 
+        let dir = path!(std::env::current_dir()? /);
+        let out_dir = dir.join(self.out_dir.clone().unwrap_or_else(env::out_dir));
+        let owns_out_dir = create_out_dir(&out_dir)? || self.out_dir.is_none();
+        ignore_artifacts(&out_dir);
+
         Ok(Project {
-            dir: path!(std::env::current_dir()? /),
-            has_pass: false,
+            dir,
+            has_pass,
             update: Update::env()?,
             has_compile_fail,
-            keep_going: true,
+            keep_going: !self.fail_fast,
+            driver: self.driver.clone().or_else(env::driver),
+            sysroot: env::sysroot().map(|path| resolve(&path)),
+            timeout: self.timeout,
+            allowed_slow: self.allowed_slow.iter().map(|path| resolve(path)).collect(),
+            run_args: self
+                .run_args
+                .iter()
+                .map(|(path, args)| (resolve(path), args.clone()))
+                .collect(),
+            warning_counts: self
+                .warning_counts
+                .iter()
+                .map(|(path, n)| (resolve(path), *n))
+                .collect(),
+            substitutions: self.substitutions.clone(),
+            ignored_lines: self.ignored_lines.clone(),
+            report_path: match &self.report_path {
+                Some(path) => Some(path.clone()),
+                None => env::report()?.then(|| out_dir.join("report.json")),
+            },
+            junit_path: self.junit_path.clone(),
+            diff: self.diff,
+            driver_args: self.driver_args.clone(),
+            driver_env: self.driver_env.clone(),
+            cache: self.cache,
+            verbose: env::verbose(),
+            quiet: self.quiet || env::quiet(),
+            prelude: self.prelude.clone(),
+            out_dir,
+            owns_out_dir,
+            match_stdout: self.match_stdout,
+            externs: self.externs.clone(),
+            opt_level: opt_level.map(str::to_owned),
+            edition: self.edition.clone(),
+            retries: self.retries,
+            overwrites: Arc::clone(&self.overwrites),
+            json_diagnostics: self.json_diagnostics,
+            unordered_stderr: self.unordered_stderr,
+            references: Arc::clone(&self.references),
+            transform_stderr: self.transform_stderr.clone(),
+            snapshot: self.snapshot_path.as_ref().map(|_| Arc::clone(&self.snapshot)),
         })
     }
 
+    // With `snapshot`, the output collected from every compile-fail test is
+    // compared with the snapshot file as a whole once all of them have run. A
+    // run that left some of them out says nothing about the whole, so it isn't
+    // compared. A run limited to some of the backends is compared on their
+    // sections only, and the sections of the other backends are kept as they
+    // are in the file.
+    fn check_snapshot(&self, report: &mut Report, backends: &[Backend]) {
+        let Some(path) = &self.snapshot_path else {
+            return;
+        };
+        let mut collected =
+            mem::take(&mut *self.snapshot.lock().unwrap_or_else(PoisonError::into_inner));
+        let partial = report.stopped_at.is_some()
+            || !report.not_run.is_empty()
+            || report
+                .records
+                .iter()
+                .any(|record| matches!(record.skip_reason, Some("filtered" | "other shard")));
+        if collected.is_empty() || partial {
+            return;
+        }
+
+        let path = match env::manifest_dir() {
+            Some(root) if path.is_relative() => root.join(path),
+            _ => path.clone(),
+        };
+        let others = Backend::ALL.iter().filter(|backend| !backends.contains(backend));
+        let others = others.map(|backend| backend.codegen()).collect::<Vec<_>>();
+        if !others.is_empty() {
+            let contents = fs::read_to_string(&path).unwrap_or_default().replace("\r\n", "\n");
+            for ((test, pass), output) in snapshot_sections(&contents) {
+                let codegen = pass.split(' ').next().unwrap_or_default();
+                if others.contains(&codegen) {
+                    collected.entry((test, pass)).or_insert(output);
+                }
+            }
+        }
+
+        let mut actual = String::new();
+        for ((test, pass), output) in &collected {
+            actual += &format!("==> {test} [{pass}]\n{output}\n");
+        }
+
+        message::begin_snapshot(&path);
+        report.total += 1;
+        match Update::env().and_then(|update| {
+            check_snapshot_file(&path, &actual, update, self.diff, &self.overwrites)
+        }) {
+            Ok(Outcome::Passed) => {}
+            Ok(Outcome::CreatedWip) => report.created_wip += 1,
+            Err(err) => {
+                report.failures += 1;
+                report.records.push(Record {
+                    name: "snapshot".to_owned(),
+                    path: path.to_string_lossy().into_owned(),
+                    backend: String::new(),
+                    expected: report::expected(Expected::CompileFail),
+                    outcome: "failed",
+                    error: Some(err.to_string()),
+                    skip_reason: None,
+                    elapsed: 0.0,
+                    output: String::new(),
+                });
+                message::test_fail(err);
+            }
+        }
+    }
+
+    // Rewrites under `TRYBUILD=overwrite` are held back until every test has
+    // run, and then either all made or, past `TRYBUILD_OVERWRITE_LIMIT`, none
+    // of them. Many changed files at once are more likely an accident than a
+    // batch of intended changes.
+    fn apply_overwrites(&self, report: &mut Report) {
+        let overwrites =
+            mem::take(&mut *self.overwrites.lock().unwrap_or_else(PoisonError::into_inner));
+        if overwrites.is_empty() {
+            return;
+        }
+        let count = overwrites.len();
+        let result = env::overwrite_limit().and_then(|limit| match limit {
+            Some(limit) if count > limit => Err(Error::OverwriteLimit(count, limit)),
+            _ => {
+                for (path, (contents, error)) in overwrites {
+                    fs::write(path, contents).map_err(error)?;
+                }
+                Ok(())
+            }
+        });
+        if let Err(err) = result {
+            report.failures += 1;
+            message::prepare_fail(err);
+        }
+    }
+
+    // Records for the tests left out of this pass, with the reason why.
+    fn skipped<const N: usize>(
+        codegen: &str,
+        skipped: [(Vec<ExpandedTest>, &'static str); N],
+    ) -> Vec<Record> {
+        let mut records = Vec::new();
+        for (tests, reason) in skipped {
+            for t in tests {
+                records.push(Record {
+                    name: t.name,
+                    path: t.test.path.to_string_lossy().into_owned(),
+                    backend: codegen.to_owned(),
+                    expected: report::expected(t.test.expected),
+                    outcome: "skipped",
+                    error: None,
+                    skip_reason: Some(reason),
+                    elapsed: 0.0,
+                    output: String::new(),
+                });
+            }
+        }
+        records
+    }
+
+    // Prints the tests that would run under `codegen` without building
+    // anything. Tests that couldn't be set up, such as a pattern matching no
+    // files, count as failures.
+    fn list(codegen: &str, tests: Vec<ExpandedTest>) -> Report {
+        print!("\n\n");
+        let len = tests.len();
+        let mut report = Report { total: len, ..Report::default() };
+        for (i, t) in tests.into_iter().enumerate() {
+            let mut record = Record {
+                name: t.name.clone(),
+                path: t.test.path.to_string_lossy().into_owned(),
+                backend: codegen.to_owned(),
+                expected: report::expected(t.test.expected),
+                outcome: "dry-run",
+                error: None,
+                skip_reason: None,
+                elapsed: 0.0,
+                output: String::new(),
+            };
+            message::begin_test(&t.test, &t.name, codegen, (i + 1, len), true);
+            match t.error {
+                Some(err) => {
+                    record.outcome = "failed";
+                    record.error = Some(err.to_string());
+                    message::test_fail(err);
+                    report.failures += 1;
+                }
+                None => println!("dry run"),
+            }
+            report.records.push(record);
+        }
+        println!();
+        report
+    }
+
     fn run_all(
         &self,
         project: &Project,
         codegen: &str,
         tests: Vec<ExpandedTest>,
     ) -> Result<Report> {
-        let mut report = Report { failures: 0, created_wip: 0 };
-
         let mut path_map = HashMap::new();
         for t in &tests {
             let src_path = project.dir.join(&t.test.path);
             path_map.insert(src_path, (&t.name, &t.test));
         }
 
-        for mut t in tests {
+        let report = self.run_parallel(project, codegen, tests, |t, progress| {
             let show_expected = false;
-            message::begin_test(&t.test, show_expected);
+            message::begin_test(&t.test, &t.name, codegen, progress, show_expected);
 
-            if t.error.is_none() {
-                t.error = check_exists(&t.test.path).err();
+            if let Some(err) = t.error {
+                return Err(err);
             }
+            check_exists(&t.test.path)?;
+            t.test.check_expectations(project, codegen)?;
+
+            t.test.build_and_check(project, &t.name, codegen)
+        });
 
-            if t.error.is_none() {
-                let output = zxc::build_test(project, &t.test.path, &t.name, codegen)?;
+        Ok(report)
+    }
 
-                let stderr = Stderr { success: output.status.success(), stderr: output.stderr };
-                match t.test.check(project, &t.name, &stderr, "") {
-                    Ok(Outcome::Passed) => {}
-                    Ok(Outcome::CreatedWip) => report.created_wip += 1,
-                    Err(error) => t.error = Some(error),
+    // Runs every test on up to `self.jobs` worker threads pulling from a
+    // shared queue. Each test's output is buffered and written out in one
+    // piece so that its block is never interleaved with other output, whether
+    // from other workers or from child processes. With a single worker the
+    // line announcing a test is shown as soon as it starts, unless quiet mode
+    // holds it back in case the test passes.
+    fn run_parallel<F>(
+        &self,
+        project: &Project,
+        codegen: &str,
+        tests: Vec<ExpandedTest>,
+        run: F,
+    ) -> Report
+    where
+        F: Fn(ExpandedTest, (usize, usize)) -> Result<Outcome> + Sync,
+    {
+        let len = tests.len();
+        let jobs = self.jobs.clamp(1, cmp::max(len, 1));
+        let queue = Mutex::new(tests.into_iter().enumerate());
+        let stop = AtomicBool::new(false);
+
+        let worker = || {
+            let mut report = Report::default();
+            while !stop.load(Ordering::Acquire) {
+                let next = queue.lock().unwrap_or_else(PoisonError::into_inner).next();
+                let Some((i, test)) = next else { break };
+                let path = test.test.path.clone();
+                let name = test.name.clone();
+                let mut record = Record {
+                    name: test.name.clone(),
+                    path: test.test.path.to_string_lossy().into_owned(),
+                    backend: codegen.to_owned(),
+                    expected: report::expected(test.test.expected),
+                    outcome: "passed",
+                    error: None,
+                    skip_reason: None,
+                    elapsed: 0.0,
+                    output: String::new(),
+                };
+                let record = |report: &mut Report| {
+                    let start = Instant::now();
+                    let result = run(test, (i + 1, len));
+                    record.elapsed = start.elapsed().as_secs_f64();
+                    match result {
+                        Ok(Outcome::Passed) => {}
+                        Ok(Outcome::CreatedWip) => {
+                            report.created_wip += 1;
+                            record.outcome = "created-wip";
+                        }
+                        Err(err) => {
+                            report.failures += 1;
+                            record.outcome =
+                                if matches!(err, Error::Flaky(_)) { "flaky" } else { "failed" };
+                            record.error = Some(err.to_string());
+                            message::test_fail(err);
+                            if project.verbose {
+                                let command = zxc::command_line(project, &path, &name, codegen);
+                                message::reproduce(&name, &path, command.ok().as_deref());
+                            }
+                            if !project.keep_going && !stop.swap(true, Ordering::AcqRel) {
+                                report.stopped_at = Some(path);
+                            }
+                        }
+                    }
+                    let passed = record.outcome == "passed";
+                    report.records.push(record);
+                    passed
+                };
+                // In quiet mode the output of a test is only known to be worth
+                // showing once it has finished.
+                let live = jobs == 1 && !project.quiet;
+                let show = |passed: &bool| !(project.quiet && *passed);
+                let (_, output) = term::with_buffer(live, || record(&mut report), show);
+                if let Some(record) = report.records.last_mut() {
+                    record.output = output;
                 }
             }
+            report
+        };
 
-            if let Some(err) = t.error {
-                report.failures += 1;
-                message::test_fail(err);
+        thread::scope(|scope| {
+            let workers = (0..jobs).map(|_| scope.spawn(worker)).collect::<Vec<_>>();
+            let mut report = Report::default();
+            for worker in workers {
+                let partial = worker.join().unwrap_or_else(|payload| panic::resume_unwind(payload));
+                report.absorb(partial);
             }
-        }
-
-        Ok(report)
+            report
+        })
     }
 
-    pub fn run(&mut self, codegen: &str) {
-        let mut tests = Self::expand_globs(&self.tests);
-        Self::filter(&mut tests);
+    // Tests comparing the backends are covered by the first pass.
+    fn run(
+        &mut self,
+        codegen: &str,
+        opt_level: Option<&str>,
+        first_pass: bool,
+        last_pass: &dyn Fn(&Report) -> bool,
+    ) -> Report {
+        let (mut tests, overridden) = self.expand_globs();
+        if first_pass {
+            for (path, from, to) in &overridden {
+                message::glob_overridden(path, *from, *to);
+            }
+        }
+        if self.keep_artifacts_named {
+            name_after_paths(&mut tests);
+        }
+        if !first_pass {
+            tests.retain(|t| !t.test.agree);
+        } else if self.check_orphaned_stderr {
+            add_orphaned_stderr(&mut tests, self.name_prefix.as_deref());
+        }
+        let mut excluded = self.exclude(&mut tests).unwrap_or_else(|err| {
+            message::prepare_fail(err);
+            panic!("tests failed");
+        });
+        if !self.report_excluded {
+            excluded.clear();
+        }
+        let sharded = Self::shard(&mut tests).unwrap_or_else(|err| {
+            message::prepare_fail(err);
+            panic!("tests failed");
+        });
+        let restricted = Self::restrict(&mut tests, codegen);
+        let filtered = Self::filter(&mut tests, codegen);
+        let skipped = Self::skipped(
+            codegen,
+            [
+                (excluded, "excluded"),
+                (sharded, "other shard"),
+                (restricted, "other backend"),
+                (filtered, "filtered"),
+            ],
+        );
+
+        if self.dry_run || env::dry_run() {
+            let mut report = Self::list(codegen, tests);
+            report.absorb_skipped(skipped);
+            return report;
+        }
 
         let (project, _lock) = (|| {
-            let mut project = self.prepare(&tests)?;
-            let lock = Lock::acquire(path!(project.dir / ".lock"))?;
+            let project = self.prepare(&tests, opt_level)?;
+            let lock = Lock::acquire(path!(project.dir / env::lock_name()))?;
+            // Later passes reuse what the earlier ones built.
+            if self.clean && first_pass {
+                zxc::clean(&project)?;
+                ignore_artifacts(&project.out_dir);
+            }
+            if let Some(before_all) = &mut self.before_all {
+                if first_pass || self.hooks_every_pass {
+                    (before_all.0)(&project).map_err(|err| Error::BeforeAll(err.to_string()))?;
+                }
+            }
             Ok((project, lock))
         })()
         .unwrap_or_else(|err| {
@@ -407,78 +2647,580 @@ impl Runner {
             panic!("tests failed");
         });
 
+        if let Ok(version) = zxc::driver_version(&project) {
+            message::report_backend_version(&version);
+        }
+
         print!("\n\n");
 
         let len = tests.len();
-        let mut report = Report { failures: 0, created_wip: 0 };
+        let mut report = Report::default();
 
         if tests.is_empty() {
             message::no_tests_enabled();
-        } else if project.keep_going && !project.has_pass {
+        } else if !project.has_pass {
             report = self.run_all(&project, codegen, tests).unwrap_or_else(|err| {
                 message::test_fail(err);
-                Report { failures: len, created_wip: 0 }
+                Report { failures: len, ..Report::default() }
             })
         } else {
-            for test in tests {
-                match test.run(&project, codegen) {
-                    Ok(Outcome::Passed) => {}
-                    Ok(Outcome::CreatedWip) => report.created_wip += 1,
-                    Err(err) => {
-                        report.failures += 1;
-                        message::test_fail(err);
-                    }
-                }
-            }
+            report = self.run_parallel(&project, codegen, tests, |test, progress| {
+                test.run(&project, codegen, progress)
+            });
         }
 
         print!("\n\n");
 
-        if report.failures > 0 {
-            panic!("{} of {} tests failed", report.failures, len);
+        if let Some(after_all) = &mut self.after_all {
+            // A fail-fast stop makes this the last pass too.
+            if last_pass(&report) || report.stopped_at.is_some() || self.hooks_every_pass {
+                (after_all.0)(&project);
+            }
+        }
+
+        report.total = len;
+        report.absorb_skipped(skipped);
+        self.records.extend(report.records.iter().cloned());
+        if let Some(report_path) = &project.report_path {
+            if let Err(err) = report::write_json(report_path, &self.records) {
+                message::prepare_fail(err);
+            }
         }
-        if report.created_wip > 0 {
-            panic!("successfully created new stderr files for {} test cases", report.created_wip,);
+        if let Some(junit_path) = &project.junit_path {
+            if let Err(err) = report::write_junit(junit_path, &self.records) {
+                message::prepare_fail(err);
+            }
         }
+
+        report
     }
 }
 
 mod zxc {
     use {
         super::Result,
-        crate::{error::Error, Project},
+        crate::{allows_slow, cache, crate_root, create_out_dir, error::Error, Project},
+        once_cell::sync::OnceCell,
         std::{
-            path::Path,
-            process::{Command, Output},
+            env::consts::EXE_SUFFIX,
+            ffi::{OsStr, OsString},
+            fs,
+            io::{self, Read},
+            path::{Path, PathBuf},
+            process::{Command, Output, Stdio},
+            thread::{self, JoinHandle},
+            time::{Duration, Instant},
         },
     };
 
-    fn zxc() -> Command {
-        if cfg!(debug_assertions) {
-            Command::new("cargo").args(["build", "--package", "driver"]).output().unwrap();
-        } else {
-            Command::new("cargo")
-                .args(["build", "--release", "--package", "driver"])
-                .output()
-                .unwrap();
+    // Must only be called while holding the project lock, since another test
+    // binary of the same crate may be using the artifacts.
+    pub fn clean(project: &Project) -> Result<()> {
+        let artifacts = &project.out_dir;
+        if !project.owns_out_dir {
+            return Err(Error::CleanOutDir(artifacts.clone()));
+        }
+        match fs::remove_dir_all(artifacts) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(Error::Io(err)),
+        }
+        create_out_dir(artifacts)?;
+        Ok(())
+    }
+
+    // With `TRYBUILD_DRIVER_WRAPPER`, the wrapper is started with the driver
+    // as its first argument, the way Cargo starts `RUSTC_WRAPPER`.
+    fn zxc(project: &Project) -> Result<Command> {
+        let driver = driver(project)?;
+        Ok(match crate::env::driver_wrapper() {
+            Some(wrapper) => {
+                let mut cmd = Command::new(wrapper);
+                cmd.arg(driver);
+                cmd
+            }
+            None => Command::new(driver),
+        })
+    }
+
+    fn driver(project: &Project) -> Result<PathBuf> {
+        if let Some(driver) = &project.driver {
+            // A bare name is looked up in PATH when the driver is started.
+            if driver.components().count() > 1 && !driver.exists() {
+                return Err(Error::DriverNotFound(driver.clone()));
+            }
+            return Ok(driver.clone());
+        }
+
+        // The driver doesn't change during a test run, so it is built once
+        // and a failed build isn't retried for every test.
+        static DRIVER: OnceCell<Result<PathBuf, String>> = OnceCell::new();
+
+        let driver = DRIVER.get_or_init(|| {
+            let mut cargo = Command::new("cargo");
+            cargo.args(["build", "--package", "driver"]);
+            let profile = if cfg!(debug_assertions) { "debug" } else { "release" };
+            if profile == "release" {
+                cargo.arg("--release");
+            }
+            let output = cargo.output().map_err(|err| format!("failed to execute cargo: {err}"))?;
+            if !output.status.success() {
+                return Err(String::from_utf8_lossy(&output.stderr).into_owned());
+            }
+            Ok(PathBuf::from(format!("../target/{profile}/{}", binary("driver"))))
+        });
+
+        match driver {
+            // Built, but not where expected, as with a different target dir.
+            Ok(driver) if !driver.exists() => Err(Error::DriverNotFound(driver.clone())),
+            Ok(driver) => Ok(driver.clone()),
+            Err(stderr) => Err(Error::DriverBuildFailed(stderr.clone())),
         }
+    }
+
+    // The file name of an executable, which on Windows needs `.exe`.
+    fn binary(name: &str) -> String {
+        format!("{name}{EXE_SUFFIX}")
+    }
 
-        Command::new("../target/debug/driver")
+    // Whether the driver died instead of exiting normally: killed by a signal,
+    // exited with the status of a Rust panic, or reported an ICE. Only a panic
+    // of the compiler's own thread counts, since diagnostics such as those of
+    // const evaluation also say that something "panicked at".
+    pub fn crashed(output: &Output) -> bool {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        output.status.code().is_none_or(|code| code == 101)
+            || stderr.contains("internal compiler error")
+            || stderr
+                .lines()
+                .any(|line| line.starts_with("thread 'rustc'") && line.contains(" panicked"))
     }
 
-    pub fn build_test(project: &Project, test: &Path, name: &str, codegen: &str) -> Result<Output> {
-        zxc()
-            .arg(project.dir.join(test))
-            .args(["--out-dir", ".artifacts"])
+    // The driver doesn't change during a test run, so only ask it once.
+    pub fn driver_version(project: &Project) -> Result<String> {
+        static VERSION: OnceCell<String> = OnceCell::new();
+
+        VERSION
+            .get_or_try_init(|| {
+                let output = zxc(project)?.arg("--version").output().map_err(Error::Cargo)?;
+                if !output.status.success() {
+                    return Err(Error::CargoFail);
+                }
+                Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+            })
+            .cloned()
+    }
+
+    // Returns the command together with the file handed to the driver, which
+    // is a generated copy of the test when there is a prelude.
+    fn build_command(
+        project: &Project,
+        test: &Path,
+        name: &str,
+        codegen: &str,
+    ) -> Result<(Command, PathBuf)> {
+        let path = project.dir.join(test);
+        let mut source = path.clone();
+        let mut crate_attrs = Vec::new();
+        if path.is_dir() {
+            // The prelude is not applied to directory crates, since a copy of
+            // their root file couldn't find the other modules.
+            source = crate_root(&path).unwrap_or_else(|| path.join("main.rs"));
+        } else if let Some(prelude) = &project.prelude {
+            let (attrs, items) = split_prelude(prelude);
+            if !items.is_empty() {
+                source = with_prelude(project, &source, name, items)?;
+            }
+            crate_attrs = attrs;
+        }
+
+        let edition = edition_file(&path.with_extension("edition"))?;
+        let mut cmd = zxc(project)?;
+        cmd.arg(&source)
+            .arg("--out-dir")
+            .arg(&project.out_dir)
             .args(["--color", "never"])
             .arg("-o")
-            .arg(name)
-            .arg(&format!("-Zcodegen-backend={codegen}"))
-            .output()
-            .map_err(Error::Cargo)
+            .arg(binary(name))
+            .arg("--edition")
+            .arg(edition.as_deref().unwrap_or(&project.edition));
+        if let Some(sysroot) = &project.sysroot {
+            cmd.arg("--sysroot").arg(sysroot);
+        }
+        if source.ends_with("lib.rs") {
+            cmd.args(["--crate-type", "lib"]);
+        }
+        if project.json_diagnostics {
+            cmd.args(["--error-format", "json"]);
+        }
+        if let Some(opt_level) = &project.opt_level {
+            cmd.arg("-C").arg(format!("opt-level={opt_level}"));
+        }
+        for (krate, rlib) in extern_rlibs(project, codegen) {
+            // The directory also holds the dependency's own dependencies.
+            if let Some(dir) = rlib.parent() {
+                cmd.arg("-L").arg(format!("dependency={}", dir.display()));
+            }
+            cmd.arg("--extern").arg(format!("{krate}={}", rlib.display()));
+        }
+        // User arguments go before the backend selection so that they
+        // can't override it.
+        for attr in crate_attrs {
+            cmd.arg(format!("-Zcrate-attr={attr}"));
+        }
+        cmd.args(&project.driver_args)
+            .arg(format!("-Zcodegen-backend={codegen}"))
+            .envs(project.driver_env.iter().map(|(key, value)| (key, value)))
+            .envs(env_file(&path.with_extension("env"))?);
+        Ok((cmd, source))
+    }
+
+    // The rlibs given to `extern_crate`, for the backend being tested.
+    fn extern_rlibs<'a>(
+        project: &'a Project,
+        codegen: &'a str,
+    ) -> impl Iterator<Item = (&'a str, PathBuf)> + 'a {
+        project.externs.iter().map(move |(krate, rlib)| {
+            let rlib = rlib.to_string_lossy().replace("{backend}", codegen);
+            (krate.as_str(), project.dir.join(rlib))
+        })
+    }
+
+    // The edition in the `.edition` file next to a test, if there is one.
+    fn edition_file(path: &Path) -> Result<Option<String>> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(Some(contents.trim().to_owned())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::Open(path.to_owned(), err)),
+        }
+    }
+
+    // Variables from the `.env` file next to a test, if there is one. Blank
+    // lines and lines starting with `#` are skipped.
+    fn env_file(path: &Path) -> Result<Vec<(String, String)>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(Error::Open(path.to_owned(), err)),
+        };
+        let mut vars = Vec::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) => vars.push((key.trim().to_owned(), value.trim().to_owned())),
+                None => return Err(Error::EnvFile(path.to_owned(), line.to_owned())),
+            }
+        }
+        Ok(vars)
+    }
+
+    // Splits the prelude into the contents of its leading inner attributes,
+    // which are handed to the driver as `-Zcrate-attr`, and the items after
+    // them. Neither touches the test's own text, so its inner attributes stay
+    // first and every line and column in diagnostics is unchanged.
+    pub fn split_prelude(prelude: &str) -> (Vec<String>, &str) {
+        let mut attrs = Vec::new();
+        let mut rest = prelude.trim_start();
+        while let Some(attr) = rest.strip_prefix("#!") {
+            let Some(attr) = attr.trim_start().strip_prefix('[') else {
+                break;
+            };
+            let mut depth = 1;
+            let Some(end) = attr.find(|ch| {
+                match ch {
+                    '[' => depth += 1,
+                    ']' => depth -= 1,
+                    _ => {}
+                }
+                depth == 0
+            }) else {
+                break;
+            };
+            attrs.push(attr[..end].trim().to_owned());
+            rest = attr[end + 1..].trim_start();
+        }
+        (attrs, rest.trim_end())
+    }
+
+    // Items may come in any order in a module, so they go after the last line
+    // of the test, on lines of their own in case it ends in a comment.
+    pub fn prelude_source(code: &str, items: &str) -> String {
+        format!("{code}\n{items}\n")
+    }
+
+    fn with_prelude(project: &Project, source: &Path, name: &str, items: &str) -> Result<PathBuf> {
+        let code = fs::read_to_string(source)?;
+        let path = project.out_dir.join(name).with_extension("rs");
+        fs::create_dir_all(&project.out_dir)?;
+        fs::write(&path, prelude_source(&code, items))?;
+        Ok(path)
     }
 
-    pub fn run_test(_: &Project, test: &str) -> Result<Output> {
-        Command::new(format!(".artifacts/{test}")).output().map_err(Error::Cargo)
+    // Point diagnostics about the generated copy back at the test itself.
+    fn unmap_source(output: &mut Output, generated: &Path, source: &Path) {
+        let (generated, source) = (generated.to_string_lossy(), source.to_string_lossy());
+        for stream in [&mut output.stdout, &mut output.stderr] {
+            let text = String::from_utf8_lossy(stream).replace(&*generated, &source);
+            *stream = text.into_bytes();
+        }
+    }
+
+    pub fn build_test(
+        project: &Project,
+        test: &Path,
+        name: &str,
+        codegen: &str,
+        use_cache: bool,
+    ) -> Result<Output> {
+        let artifacts = &project.out_dir;
+        let path = project.dir.join(test);
+        let (mut cmd, source) = build_command(project, test, name, codegen)?;
+
+        // A directory crate is hashed as a whole, not just its root file.
+        let input = if path.is_dir() { &path } else { &source };
+        let key = if project.cache && use_cache {
+            // A rebuilt dependency changes the result as much as the test.
+            let mut inputs = vec![driver(project)?];
+            inputs.extend(extern_rlibs(project, codegen).map(|(_, rlib)| rlib));
+            let inputs = inputs.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+            cache::key(&cmd, input, &inputs)
+        } else {
+            None
+        };
+        if let Some(output) = key.and_then(|key| cache::load(artifacts, key, &binary(name))) {
+            return Ok(output);
+        }
+
+        // Remove the binary of a previous run so that a driver ignoring `-o`
+        // can't leave a stale one to be run instead.
+        let artifact = artifacts.join(binary(name));
+        let _ = fs::remove_file(&artifact);
+
+        let mut output = cmd.output().map_err(Error::Cargo)?;
+        if source.starts_with(artifacts) {
+            unmap_source(&mut output, &source, &path);
+        }
+        let is_lib = source.ends_with("lib.rs");
+        if output.status.success() && !is_lib && !artifact.exists() {
+            return Err(Error::MissingArtifact(artifact));
+        }
+        if let Some(key) = key {
+            // Failing to populate the cache only costs a rebuild next time.
+            let _ = cache::store(artifacts, key, &binary(name), &output);
+        }
+        Ok(output)
+    }
+
+    // The driver invocation of `build_test` in a form that can be pasted into
+    // a shell to reproduce it by hand.
+    pub fn command_line(
+        project: &Project,
+        test: &Path,
+        name: &str,
+        codegen: &str,
+    ) -> Result<String> {
+        let (cmd, _) = build_command(project, test, name, codegen)?;
+        let mut line = String::new();
+        for (key, value) in cmd.get_envs() {
+            if let Some(value) = value {
+                line += &format!("{}={} ", shell_quote(key), shell_quote(value));
+            }
+        }
+        line += &shell_quote(cmd.get_program());
+        for arg in cmd.get_args() {
+            line.push(' ');
+            line += &shell_quote(arg);
+        }
+        Ok(line)
+    }
+
+    fn shell_quote(arg: &OsStr) -> String {
+        let arg = arg.to_string_lossy();
+        let plain = |ch: char| ch.is_ascii_alphanumeric() || "-_./=:,+@%".contains(ch);
+        if !arg.is_empty() && arg.chars().all(plain) {
+            arg.into_owned()
+        } else {
+            format!("'{}'", arg.replace('\'', "'\\''"))
+        }
+    }
+
+    pub fn run_test(
+        project: &Project,
+        path: &Path,
+        test: &str,
+        codegen: &str,
+        args: &[OsString],
+    ) -> Result<Output> {
+        let mut cmd = Command::new(project.out_dir.join(binary(test)));
+        cmd.args(args);
+        let timeout = match project.timeout {
+            Some(timeout) if !allows_slow(&project.allowed_slow, path) => timeout,
+            _ => return cmd.output().map_err(Error::Cargo),
+        };
+
+        let mut child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(Error::Cargo)?;
+        let stdout = read_to_end(child.stdout.take());
+        let stderr = read_to_end(child.stderr.take());
+
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(Error::Cargo)? {
+                break status;
+            }
+            if start.elapsed() >= timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Error::Timeout(path.to_owned(), codegen.to_owned(), timeout));
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+        Ok(Output { status, stdout, stderr })
+    }
+
+    // Drain a child pipe on a separate thread so that a chatty child can't
+    // block on a full pipe while we wait for it to exit.
+    fn read_to_end(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buf);
+            }
+            buf
+        })
+    }
+}
+
+#[test]
+fn test_insert_explicit_and_glob() {
+    let test = |expected| Test {
+        path: PathBuf::from("tests/ui/a.rs"),
+        expected,
+        code: None,
+        agree: false,
+        reference: false,
+        silent: false,
+        backends: Vec::new(),
+    };
+    let expand = |registrations: &[(Expected, bool)], preserve_order| {
+        let mut set = ExpandedTestSet::new(preserve_order);
+        for &(expected, is_from_glob) in registrations {
+            set.insert(test(expected), None, is_from_glob);
+        }
+        let (vec, _) = set.into_vec(None);
+        vec.into_iter().map(|t| (t.test.expected, t.is_from_glob)).collect::<Vec<_>>()
+    };
+
+    for preserve_order in [false, true] {
+        let explicit_then_glob = [(Expected::Pass, false), (Expected::Pass, true)];
+        assert_eq!(expand(&explicit_then_glob, preserve_order), [(Expected::Pass, false)]);
+        let glob_then_explicit = [(Expected::Pass, true), (Expected::CompileFail, false)];
+        assert_eq!(expand(&glob_then_explicit, preserve_order), [(Expected::CompileFail, false)]);
+    }
+}
+
+#[test]
+fn test_insert_conflicting_glob() {
+    let test = |expected| Test {
+        path: PathBuf::from("tests/ui/a.rs"),
+        expected,
+        code: None,
+        agree: false,
+        reference: false,
+        silent: false,
+        backends: Vec::new(),
+    };
+
+    for preserve_order in [false, true] {
+        let mut set = ExpandedTestSet::new(preserve_order);
+        set.insert(test(Expected::CompileFail), None, false);
+        set.insert(test(Expected::Pass), None, true);
+        let (vec, overridden) = set.into_vec(None);
+        assert_eq!(vec.len(), 1);
+        assert!(matches!(vec[0].error, Some(Error::ConflictingExpectation(_))));
+        assert!(overridden.is_empty());
+
+        let mut set = ExpandedTestSet::new(preserve_order);
+        set.insert(test(Expected::Pass), None, true);
+        set.insert(test(Expected::Pass), None, true);
+        set.insert(test(Expected::CompileFail), None, true);
+        let (vec, _) = set.into_vec(None);
+        assert_eq!(vec.len(), 1);
+        assert!(matches!(vec[0].error, Some(Error::ConflictingExpectation(_))));
     }
 }
+
+#[test]
+fn test_snapshot_sections() {
+    let contents =
+        "==> ui/a.rs [cranelift]\nerror: a\n\n==> ui/a.rs [llvm opt-level=2]\nerror: b\n\n";
+    let sections = snapshot_sections(contents);
+    let key = |test: &str, pass: &str| (test.to_owned(), pass.to_owned());
+    assert_eq!(sections[&key("ui/a.rs", "cranelift")], "error: a\n");
+    assert_eq!(sections[&key("ui/a.rs", "llvm opt-level=2")], "error: b\n");
+    assert_eq!(sections.len(), 2);
+}
+
+#[test]
+fn test_prelude_keeps_inner_attributes_first() {
+    let prelude = "#![feature(never_type)]\n#![cfg_attr(all(), allow(unused))]\nuse std::fmt;\n";
+    let (attrs, items) = zxc::split_prelude(prelude);
+    assert_eq!(attrs, ["feature(never_type)", "cfg_attr(all(), allow(unused))"]);
+    assert_eq!(items, "use std::fmt;");
+
+    let code = "#![allow(dead_code)]\nfn main() {} // end";
+    let source = zxc::prelude_source(code, items);
+    assert!(source.starts_with(code));
+    assert_eq!(source.lines().nth(2), Some("use std::fmt;"));
+
+    assert_eq!(zxc::split_prelude("use std::fmt;"), (Vec::new(), "use std::fmt;"));
+}
+
+#[test]
+fn test_filter_tokens() {
+    let kept = |filters: &[&str]| {
+        let mut set = ExpandedTestSet::new(true);
+        for (path, expected) in [
+            ("tests/ui/a.rs", Expected::Pass),
+            ("tests/ui/b.rs", Expected::CompileFail),
+            ("tests/ui/ab.rs", Expected::Warn),
+            ("tests/other/a.rs", Expected::CompileFail),
+        ] {
+            let test = Test {
+                path: PathBuf::from(path),
+                expected,
+                code: None,
+                agree: false,
+                reference: false,
+                silent: false,
+                backends: Vec::new(),
+            };
+            set.insert(test, None, false);
+        }
+        let (mut tests, _) = set.into_vec(None);
+        let filters = filters.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+        Runner::filter_by(&mut tests, Backend::Llvm.codegen(), &filters);
+        tests.into_iter().map(|t| t.test.path.to_string_lossy().into_owned()).collect::<Vec<_>>()
+    };
+
+    // Within a kind, any token may match.
+    assert_eq!(kept(&["@pass @warn"]), ["tests/ui/a.rs", "tests/ui/ab.rs"]);
+    assert_eq!(
+        kept(&["b.rs", "=tests/other/a.rs"]),
+        ["tests/ui/b.rs", "tests/ui/ab.rs", "tests/other/a.rs"]
+    );
+    // Across kinds, every kind must match, in one argument or several.
+    assert_eq!(kept(&["@fail ui/"]), ["tests/ui/b.rs"]);
+    assert_eq!(kept(&["@fail", "ui/"]), ["tests/ui/b.rs"]);
+    assert_eq!(kept(&["@fail @llvm"]), ["tests/ui/b.rs", "tests/other/a.rs"]);
+    assert!(kept(&["@fail @cranelift"]).is_empty());
+}
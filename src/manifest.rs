@@ -0,0 +1,90 @@
+use {
+    crate::{
+        env::Backend,
+        error::{Error, Result},
+        Expected,
+    },
+    serde_derive::Deserialize,
+    std::{fs, path::Path},
+};
+
+// A list of tests to register, for suites too large to spell out one call at a
+// time:
+//
+//     [[test]]
+//     path = "ui/mismatch.rs"
+//     expect = "compile-fail"
+//     code = "E0308"
+//     backends = ["llvm"]
+//
+// `expect` is one of `pass`, `compile-fail`, `run-fail` and `warn`. Without
+// `backends` a test runs under all of them. Paths are relative to the file.
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    test: Vec<Entry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub path: String,
+    expect: Expect,
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub backends: Vec<Backend>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Expect {
+    Pass,
+    CompileFail,
+    RunFail,
+    Warn,
+}
+
+impl Entry {
+    pub fn expected(&self) -> Expected {
+        match self.expect {
+            Expect::Pass => Expected::Pass,
+            Expect::CompileFail => Expected::CompileFail,
+            Expect::RunFail => Expected::RunFail,
+            Expect::Warn => Expected::Warn,
+        }
+    }
+}
+
+pub fn load(path: &Path) -> Result<Vec<Entry>> {
+    let contents = fs::read_to_string(path).map_err(|err| Error::Open(path.to_owned(), err))?;
+    parse(&contents).map_err(|message| Error::Manifest(path.to_owned(), message))
+}
+
+fn parse(contents: &str) -> std::result::Result<Vec<Entry>, String> {
+    let manifest: Manifest = basic_toml::from_str(contents).map_err(|err| err.to_string())?;
+    for entry in &manifest.test {
+        if entry.code.is_some() && entry.expected() != Expected::CompileFail {
+            return Err(format!("{}: `code` only applies to compile-fail tests", entry.path));
+        }
+    }
+    Ok(manifest.test)
+}
+
+#[test]
+fn test_parse() {
+    let entries = parse(concat!(
+        "[[test]]\npath = \"ui/a.rs\"\nexpect = \"compile-fail\"\ncode = \"E0308\"\n",
+        "[[test]]\npath = \"ui/b.rs\"\nexpect = \"pass\"\nbackends = [\"llvm\"]\n",
+    ))
+    .unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].expected(), Expected::CompileFail);
+    assert_eq!(entries[0].code.as_deref(), Some("E0308"));
+    assert_eq!(entries[1].backends, [Backend::Llvm]);
+
+    assert!(parse("[[test]]\npath = \"a.rs\"\nexpect = \"pass\"\ncode = \"E0308\"\n").is_err());
+    assert!(parse("[[test]]\npath = \"a.rs\"\nexpect = \"fail\"\n").is_err());
+}
@@ -1,10 +1,15 @@
 use {
     crate::{
-        diff::{Diff, Render},
+        diff::{Diff, Limits, Render},
+        env::Backend,
         error::Error,
-        normalize, print, println, term, Expected, Test,
+        normalize, print, println,
+        report::{self, Record},
+        term,
+        unordered::Difference,
+        Expected, Test,
     },
-    std::{env, path::Path, process::Output},
+    std::{env, path::Path, process::Output, time::Duration},
     termcolor::Color::{self, *},
 };
 
@@ -15,9 +20,18 @@ pub(crate) enum Level {
 
 pub(crate) use self::Level::*;
 
-pub(crate) fn report_codegen(codegen: &str) {
+pub(crate) fn report_codegen(codegen: &str, opt_level: Option<&str>) {
     term::bold_color(Cyan);
-    println!("{codegen}");
+    match opt_level {
+        Some(opt_level) => println!("{codegen} (opt-level={opt_level})"),
+        None => println!("{codegen}"),
+    }
+    term::reset();
+}
+
+pub(crate) fn report_backend_version(version: &str) {
+    term::color(Cyan);
+    println!("{version}");
     term::reset();
 }
 
@@ -46,6 +60,36 @@ pub(crate) fn test_fail(err: Error) {
     println!();
 }
 
+pub(crate) fn reproduce(name: &str, path: &Path, command: Option<&str>) {
+    print!("note: ");
+    term::bold();
+    print!("{}", name);
+    term::reset();
+    println!(" -> {}", path.to_string_lossy());
+    if let Some(command) = command {
+        println!("      {}", command);
+    }
+    println!();
+}
+
+pub(crate) fn lock_unavailable(path: &Path, error: &std::io::Error) {
+    term::bold_color(Yellow);
+    print!("WARNING");
+    term::reset();
+    println!(": cannot create {}: {}", path.display(), error);
+    println!("tests in other crates sharing this directory will not be serialized");
+    println!();
+}
+
+pub(crate) fn gitignore_unavailable(path: &Path, error: &std::io::Error) {
+    term::bold_color(Yellow);
+    print!("WARNING");
+    term::reset();
+    println!(": cannot create {}: {}", path.display(), error);
+    println!("built tests may end up in version control");
+    println!();
+}
+
 pub(crate) fn no_tests_enabled() {
     term::color(Yellow);
     println!("There are no trybuild tests enabled yet.");
@@ -58,10 +102,31 @@ pub(crate) fn ok() {
     term::reset();
 }
 
-pub(crate) fn begin_test(test: &Test, show_expected: bool) {
+pub(crate) fn begin_snapshot(path: &Path) {
+    term::color(Cyan);
+    print!("[snapshot] ");
+    term::reset();
+    term::bold();
+    print!("{}", path.display());
+    term::reset();
+    print!(" ... ");
+}
+
+// `progress` is the position of the test in the pass, counting from 1, and the
+// number of tests in it.
+pub(crate) fn begin_test(
+    test: &Test,
+    name: &str,
+    codegen: &str,
+    progress: (usize, usize),
+    show_expected: bool,
+) {
     let display_name = test.path.as_os_str().to_string_lossy();
 
-    print!("test ");
+    term::color(Cyan);
+    print!("[{} {}/{}] ", codegen, progress.0, progress.1);
+    term::reset();
+    print!("{} ", name);
     term::bold();
     print!("{}", display_name);
     term::reset();
@@ -70,16 +135,23 @@ pub(crate) fn begin_test(test: &Test, show_expected: bool) {
         match test.expected {
             Expected::Pass => print!(" [should pass]"),
             Expected::CompileFail => print!(" [should fail to compile]"),
+            Expected::RunFail => print!(" [should fail at runtime]"),
+            Expected::Warn => print!(" [should warn]"),
         }
     }
 
     print!(" ... ");
+    term::flush();
 }
 
-pub(crate) fn failed_to_build(stderr: &str) {
+pub(crate) fn failed_to_build(stderr: &str, command: Option<&str>) {
     term::bold_color(Red);
     println!("error");
     snippet(Red, stderr);
+    if let Some(command) = command {
+        println!("note: the failed command was");
+        println!("      {}", command);
+    }
     println!();
 }
 
@@ -92,52 +164,138 @@ pub(crate) fn should_not_have_compiled() {
     println!();
 }
 
+pub(crate) fn driver_ice(stderr: &str) {
+    term::bold_color(Red);
+    println!("error");
+    term::color(Red);
+    println!("The driver crashed instead of reporting a compile error:");
+    term::reset();
+    snippet(Red, stderr);
+    println!();
+}
+
+pub(crate) fn missing_error_code(code: &str, stderr: &str) {
+    term::bold_color(Red);
+    println!("error");
+    term::color(Red);
+    println!("Expected the compiler error to contain {}:", code);
+    term::reset();
+    snippet(Red, stderr);
+    println!();
+}
+
+pub(crate) fn wrong_warning_count(expected: usize, actual: usize, stderr: &str) {
+    term::bold_color(Red);
+    println!("error");
+    term::color(Red);
+    println!("Expected {} warnings, but the compiler reported {}:", expected, actual);
+    term::reset();
+    snippet(Red, stderr);
+    println!();
+}
+
+pub(crate) fn escaped_invalid_utf8() {
+    term::color(Yellow);
+    print!("(stderr is not valid UTF-8; the invalid bytes are written as \\xNN) ");
+    term::reset();
+}
+
+pub(crate) fn no_longer_produced(stderr_path: &Path, expected: &str) {
+    term::bold_color(Blue);
+    println!("NO LONGER PRODUCED (from `{}`):", stderr_path.to_string_lossy());
+    snippet(Blue, expected);
+    println!();
+}
+
+pub(crate) fn should_have_failed_at_runtime(warnings: &str, output: &Output) {
+    term::bold_color(Red);
+    println!("error");
+    term::color(Red);
+    println!("Expected test case to fail at runtime, but it exited successfully.");
+    term::reset();
+    println!();
+
+    self::warnings(warnings);
+
+    let stdout = normalize::trim(&output.stdout);
+    if !stdout.is_empty() {
+        term::bold_color(Red);
+        println!("STDOUT:");
+        snippet(Red, &stdout);
+        println!();
+    }
+}
+
 pub(crate) fn write_stderr_wip(wip_path: &Path, stderr_path: &Path, stderr: &str) {
+    write_wip("output", wip_path, stderr_path, stderr);
+}
+
+pub(crate) fn write_stdout_wip(wip_path: &Path, stdout_path: &Path, stdout: &str) {
+    write_wip("stdout of the test", wip_path, stdout_path, stdout);
+}
+
+fn write_wip(what: &str, wip_path: &Path, expected_path: &Path, contents: &str) {
     let wip_path = wip_path.to_string_lossy();
-    let stderr_path = stderr_path.to_string_lossy();
+    let expected_path = expected_path.to_string_lossy();
 
     term::bold_color(Yellow);
     println!("wip");
     println!();
     print!("NOTE");
     term::reset();
-    println!(": writing the following output to `{}`.", wip_path);
-    println!("Move this file to `{}` to accept it as correct.", stderr_path,);
-    snippet(Yellow, stderr);
+    println!(": writing the following {} to `{}`.", what, wip_path);
+    println!("Move this file to `{}` to accept it as correct.", expected_path);
+    snippet(Yellow, contents);
+    println!();
+}
+
+pub(crate) fn saved_build_output(wip_path: &Path) {
+    println!("note: the build output was saved to `{}`", wip_path.display());
     println!();
 }
 
 pub(crate) fn overwrite_stderr(stderr_path: &Path, stderr: &str) {
-    let stderr_path = stderr_path.to_string_lossy();
+    overwrite("output", stderr_path, stderr);
+}
+
+pub(crate) fn overwrite_stdout(stdout_path: &Path, stdout: &str) {
+    overwrite("stdout of the test", stdout_path, stdout);
+}
+
+fn overwrite(what: &str, path: &Path, contents: &str) {
+    let path = path.to_string_lossy();
 
     term::bold_color(Yellow);
     println!("wip");
     println!();
     print!("NOTE");
     term::reset();
-    println!(": writing the following output to `{}`.", stderr_path);
-    snippet(Yellow, stderr);
+    println!(": writing the following {} to `{}`.", what, path);
+    snippet(Yellow, contents);
     println!();
 }
 
-pub(crate) fn mismatch(expected: &str, actual: &str) {
+pub(crate) fn mismatch(expected: &str, actual: &str, limits: Limits) {
+    compare_mismatch(("EXPECTED", "ACTUAL OUTPUT"), expected, actual, limits);
+}
+
+pub(crate) fn stdout_mismatch(expected: &str, actual: &str, limits: Limits) {
+    compare_mismatch(("EXPECTED STDOUT", "ACTUAL STDOUT"), expected, actual, limits);
+}
+
+fn compare_mismatch(labels: (&str, &str), expected: &str, actual: &str, limits: Limits) {
     term::bold_color(Red);
     println!("mismatch");
     term::reset();
     println!();
-    let diff = if env::var_os("TERM").map_or(true, |term| term == "dumb") {
-        // No diff in dumb terminal or when TERM is unset.
-        None
-    } else {
-        Diff::compute(expected, actual)
-    };
+    let diff = diff(expected, actual, limits);
     term::bold_color(Blue);
-    println!("EXPECTED:");
-    snippet_diff(Blue, expected, diff.as_ref());
+    println!("{}:", labels.0);
+    snippet_diff(Blue, expected, diff.as_ref().map(|diff| (diff, Red)));
     println!();
     term::bold_color(Red);
-    println!("ACTUAL OUTPUT:");
-    snippet_diff(Red, actual, diff.as_ref());
+    println!("{}:", labels.1);
+    snippet_diff(Red, actual, diff.as_ref().map(|diff| (diff, Green)));
     print!("note: If the ");
     term::color(Red);
     print!("actual output");
@@ -147,8 +305,100 @@ pub(crate) fn mismatch(expected: &str, actual: &str) {
     println!();
 }
 
-pub(crate) fn output(warnings: &str, output: &Output) {
-    let success = output.status.success();
+pub(crate) fn backends_disagree(a: (&str, &str), b: (&str, &str), limits: Limits) {
+    term::bold_color(Red);
+    println!("backends disagree");
+    term::reset();
+    println!();
+    let diff = diff(a.1, b.1, limits);
+    term::bold_color(Blue);
+    println!("{}:", a.0.to_uppercase());
+    snippet_diff(Blue, a.1, diff.as_ref().map(|diff| (diff, Red)));
+    println!();
+    term::bold_color(Red);
+    println!("{}:", b.0.to_uppercase());
+    snippet_diff(Red, b.1, diff.as_ref().map(|diff| (diff, Green)));
+    println!();
+}
+
+pub(crate) fn flaky(attempt: usize, first: &str, matched: &str, limits: Limits) {
+    term::bold_color(Yellow);
+    println!("flaky");
+    term::reset();
+    println!();
+    println!("note: Rebuild {attempt} matched the expected output, so this test's output is");
+    println!("      not deterministic.");
+    println!();
+    let diff = diff(first, matched, limits);
+    term::bold_color(Red);
+    println!("FIRST BUILD:");
+    snippet_diff(Red, first, diff.as_ref().map(|diff| (diff, Red)));
+    println!();
+    term::bold_color(Blue);
+    println!("MATCHING BUILD:");
+    snippet_diff(Blue, matched, diff.as_ref().map(|diff| (diff, Green)));
+    println!();
+}
+
+pub(crate) fn unordered_mismatch(difference: &Difference) {
+    term::bold_color(Red);
+    println!("mismatch");
+    term::reset();
+    println!();
+    if !difference.missing.is_empty() {
+        term::bold_color(Blue);
+        println!("MISSING:");
+        snippet(Blue, &blocks(&difference.missing));
+        println!();
+    }
+    if !difference.extra.is_empty() {
+        term::bold_color(Red);
+        println!("UNEXPECTED:");
+        snippet(Red, &blocks(&difference.extra));
+        println!();
+    }
+    println!("note: Diagnostics are compared regardless of their order. If the actual output");
+    println!("      is correct you can bless it by rerunning with TRYBUILD=overwrite");
+    println!();
+
+    fn blocks(blocks: &[&str]) -> String {
+        blocks.iter().map(|block| format!("{block}\n")).collect::<Vec<_>>().join("\n")
+    }
+}
+
+pub(crate) fn contains_mismatch(contains_path: &Path, expected: &str, actual: &str) {
+    term::bold_color(Red);
+    println!("mismatch");
+    term::reset();
+    println!();
+    term::bold_color(Blue);
+    println!("EXPECTED TO CONTAIN ({}):", contains_path.display());
+    snippet(Blue, &format!("{expected}\n"));
+    println!();
+    term::bold_color(Red);
+    println!("ACTUAL OUTPUT:");
+    snippet(Red, actual);
+    println!("note: Only a .stderr.contains file was found, so the actual output just has to");
+    println!("      contain its text");
+    println!();
+}
+
+pub(crate) fn whitespace_only_mismatch(stderr_path: &Path) {
+    term::bold_color(Red);
+    println!("mismatch");
+    term::reset();
+    println!();
+    print!("note: The actual output differs from `{}` ", stderr_path.to_string_lossy());
+    term::bold();
+    print!("only in whitespace");
+    term::reset();
+    println!(",");
+    println!("      such as trailing spaces or blank lines. If the actual output is correct");
+    println!("      you can bless it by rerunning your test with TRYBUILD=overwrite");
+    println!();
+}
+
+pub(crate) fn output(warnings: &str, output: &Output, success: bool) {
     let stdout = normalize::trim(&output.stdout);
     let stderr = normalize::trim(&output.stderr);
     let has_output = !stdout.is_empty() || !stderr.is_empty();
@@ -172,9 +422,41 @@ pub(crate) fn output(warnings: &str, output: &Output) {
     }
 
     self::warnings(warnings);
+    streams(if success { Yellow } else { Red }, stdout, stderr);
+}
 
-    let color = if success { Yellow } else { Red };
+pub(crate) fn not_silent(warnings: &str, output: &Output) {
+    term::bold_color(Red);
+    println!("error");
+    term::color(Red);
+    println!("Expected test case to print nothing, but it printed the following.");
+    term::reset();
+    println!();
+
+    self::warnings(warnings);
+    streams(Red, normalize::trim(&output.stdout), normalize::trim(&output.stderr));
+}
 
+pub(crate) fn wrong_exit_code(warnings: &str, expected: i32, output: &Output) {
+    term::bold_color(Red);
+    println!("error");
+    term::color(Red);
+    match output.status.code() {
+        Some(code) => {
+            println!("Expected exit code {}, but the test case exited with {}.", expected, code)
+        }
+        None => {
+            println!("Expected exit code {}, but the test case was killed by a signal.", expected)
+        }
+    }
+    term::reset();
+    println!();
+
+    self::warnings(warnings);
+    streams(Red, normalize::trim(&output.stdout), normalize::trim(&output.stderr));
+}
+
+fn streams(color: Color, stdout: String, stderr: String) {
     for (name, content) in &[("STDOUT", stdout), ("STDERR", stderr)] {
         if !content.is_empty() {
             term::bold_color(color);
@@ -210,11 +492,109 @@ pub(crate) fn warnings(warnings: &str) {
     println!();
 }
 
+pub(crate) fn timings(backends: &[(Backend, Duration)], records: &[Record]) {
+    const SLOWEST: usize = 5;
+
+    term::bold_color(Cyan);
+    println!("TIMINGS:");
+    term::reset();
+    let totals = backends
+        .iter()
+        .map(|(backend, elapsed)| format!("{}: {:.1}s", backend.display(), elapsed.as_secs_f64()))
+        .collect::<Vec<_>>();
+    println!("{}", totals.join(", "));
+
+    let mut slowest = records.iter().collect::<Vec<_>>();
+    slowest.sort_by(|a, b| b.elapsed.total_cmp(&a.elapsed));
+    for record in slowest.into_iter().take(SLOWEST) {
+        println!("  {:>7.2}s  [{}] {}", record.elapsed, record.backend, record.path);
+    }
+    println!();
+}
+
+pub(crate) fn glob_overridden(path: &Path, from: Expected, to: Expected) {
+    println!(
+        "note: {} matches a {} pattern but is registered as {} on its own",
+        path.display(),
+        report::expected(from),
+        report::expected(to),
+    );
+}
+
+pub(crate) fn disk_usage(dir: &Path, bytes: u64, files: usize) {
+    let mib = bytes as f64 / (1024.0 * 1024.0);
+    println!("note: {} holds {} files, {:.1} MiB in total", dir.display(), files, mib);
+    println!();
+}
+
+pub(crate) fn run_failed(failure: &str) {
+    term::bold_color(Red);
+    print!("error");
+    term::reset();
+    println!(": {failure}");
+}
+
+pub(crate) fn tally(ran: usize, skipped: usize, failed: usize, total: usize) {
+    println!("ran {ran}, skipped {skipped}, failed {failed} of {total} total");
+    println!();
+}
+
+pub(crate) fn summary(backends: &[(Backend, Duration)], records: &[Record]) {
+    let failed = |backend: Backend| {
+        records
+            .iter()
+            .filter(move |record| record.backend == backend.codegen())
+            .filter(|record| matches!(record.outcome, "failed" | "flaky"))
+    };
+
+    term::bold_color(Red);
+    println!("FAILURES:");
+    term::reset();
+    let counts = backends
+        .iter()
+        .map(|(backend, _)| format!("{}: {} failed", backend.display(), failed(*backend).count()))
+        .collect::<Vec<_>>();
+    println!("{}", counts.join(", "));
+    for (backend, _) in backends {
+        for record in failed(*backend) {
+            match record.outcome {
+                "flaky" => println!("  [{}] {} (flaky)", record.backend, record.path),
+                _ => println!("  [{}] {}", record.backend, record.path),
+            }
+        }
+    }
+    println!();
+}
+
+pub(crate) fn backend_divergence(divergences: &[(&str, Vec<(&str, &str)>)]) {
+    term::bold_color(Yellow);
+    println!("BACKEND DIVERGENCE:");
+    term::reset();
+    for (path, backends) in divergences {
+        let outcomes = backends
+            .iter()
+            .map(|(backend, outcome)| format!("{outcome} under {backend}"))
+            .collect::<Vec<_>>();
+        println!("  {}: {}", path, outcomes.join(", "));
+    }
+    println!();
+}
+
+// No diff in a dumb terminal or when TERM is unset.
+fn diff<'a>(expected: &'a str, actual: &'a str, limits: Limits) -> Option<Diff<'a>> {
+    if env::var_os("TERM").is_none_or(|term| term == "dumb") {
+        return None;
+    }
+    Diff::compute(expected, actual, limits)
+}
+
 fn snippet(color: Color, content: &str) {
     snippet_diff(color, content, None);
 }
 
-fn snippet_diff(color: Color, content: &str, diff: Option<&Diff>) {
+// With a diff, text the two sides have in common is left uncolored and the
+// text only this side has is highlighted in `unique`.
+fn snippet_diff(color: Color, content: &str, diff: Option<(&Diff, Color)>) {
     fn dotted_line() {
         println!("{}", "┈".repeat(60));
     }
@@ -223,16 +603,16 @@ fn snippet_diff(color: Color, content: &str, diff: Option<&Diff>) {
     dotted_line();
 
     match diff {
-        Some(diff) => {
+        Some((diff, unique)) => {
             for chunk in diff.iter(content) {
                 match chunk {
                     Render::Common(s) => {
-                        term::color(color);
+                        term::reset();
                         print!("{}", s);
                     }
                     Render::Unique(s) => {
-                        term::bold_color(color);
-                        print!("\x1B[7m{}", s);
+                        term::bold_color(unique);
+                        print!("{}", s);
                     }
                 }
             }
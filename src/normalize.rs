@@ -1,3 +1,28 @@
+use crate::directory::Directory;
+
+pub struct Context<'a> {
+    pub project_dir: &'a Directory,
+    pub test_name: &'a str,
+    pub substitutions: &'a [Substitution],
+    pub ignored_lines: &'a [Substitution],
+}
+
+// A user-provided replacement rule. The pattern is matched literally except
+// for the placeholders `{HASH}`, which matches 16 lowercase hex digits, and
+// `{NUM}`, which matches a run of decimal digits.
+#[derive(Clone, Debug)]
+pub struct Substitution {
+    pattern: Vec<Segment>,
+    replacement: String,
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Hash,
+    Num,
+}
+
 pub fn trim<S: AsRef<[u8]>>(output: S) -> String {
     let bytes = output.as_ref();
     let mut normalized = String::from_utf8_lossy(bytes).into_owned();
@@ -11,3 +36,223 @@ pub fn trim<S: AsRef<[u8]>>(output: S) -> String {
 
     normalized
 }
+
+// Decodes driver output, writing each byte that isn't valid UTF-8 as `\xNN`
+// rather than U+FFFD so that the raw bytes still show in, and are compared
+// through, the expected output files. Returns whether anything was escaped.
+pub fn escape_invalid_utf8(bytes: &[u8]) -> (String, bool) {
+    let mut text = String::with_capacity(bytes.len());
+    let mut escaped = false;
+    for chunk in bytes.utf8_chunks() {
+        text.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            text.push_str(&format!("\\x{:02X}", byte));
+            escaped = true;
+        }
+    }
+    (text, escaped)
+}
+
+// Whether the two texts are the same once runs of whitespace, including blank
+// lines and trailing spaces, are collapsed.
+pub fn whitespace_eq(a: &str, b: &str) -> bool {
+    a.split_whitespace().eq(b.split_whitespace())
+}
+
+// Make captured diagnostics portable between machines: the project directory
+// becomes `$DIR/`, the generated test name becomes `$TEST`, and on Windows
+// path separators become forward slashes. A line that was overwritten using
+// bare carriage returns, like a progress spinner, keeps only its final text.
+pub fn diagnostics(output: &str, context: &Context) -> String {
+    let mut normalized = collapse_carriage_returns(&output.replace("\r\n", "\n"));
+    if cfg!(windows) {
+        normalized = normalized.replace('\\', "/");
+    }
+
+    let mut dirs = vec![path_to_string(context.project_dir)];
+    if let Ok(canonical) = context.project_dir.canonicalize() {
+        dirs.push(path_to_string(&canonical));
+    }
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.len()));
+    for dir in dirs {
+        normalized = normalized.replace(&dir, "$DIR/");
+    }
+
+    if !context.test_name.is_empty() {
+        normalized = normalized.replace(context.test_name, "$TEST");
+    }
+
+    for substitution in context.substitutions {
+        normalized = substitution.apply(&normalized);
+    }
+
+    drop_ignored_lines(&normalized, context.ignored_lines)
+}
+
+// Removes every line that contains one of `patterns`, from the expected output
+// as well as the actual one, so that those lines never cause a mismatch.
+pub fn drop_ignored_lines(text: &str, patterns: &[Substitution]) -> String {
+    if patterns.is_empty() {
+        return text.to_owned();
+    }
+    let lines = text.split_inclusive('\n');
+    lines.filter(|line| !patterns.iter().any(|pattern| pattern.occurs_in(line))).collect()
+}
+
+fn collapse_carriage_returns(output: &str) -> String {
+    if !output.contains('\r') {
+        return output.to_owned();
+    }
+    let mut collapsed = String::with_capacity(output.len());
+    for line in output.split_inclusive('\n') {
+        let (line, newline) = match line.strip_suffix('\n') {
+            Some(line) => (line, "\n"),
+            None => (line, ""),
+        };
+        // A trailing `\r` returns to the start without writing anything over
+        // the line.
+        let line = line.trim_end_matches('\r');
+        collapsed += line.rsplit('\r').next().unwrap_or_default();
+        collapsed += newline;
+    }
+    collapsed
+}
+
+impl Substitution {
+    pub fn new(pattern: &str, replacement: &str) -> Self {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = pattern;
+        while !rest.is_empty() {
+            let placeholder = if rest.starts_with("{HASH}") {
+                Some(Segment::Hash)
+            } else if rest.starts_with("{NUM}") {
+                Some(Segment::Num)
+            } else {
+                None
+            };
+            match placeholder {
+                Some(placeholder) => {
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    rest = &rest[rest.find('}').unwrap() + 1..];
+                    segments.push(placeholder);
+                }
+                None => {
+                    let ch = rest.chars().next().unwrap();
+                    literal.push(ch);
+                    rest = &rest[ch.len_utf8()..];
+                }
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Substitution { pattern: segments, replacement: replacement.to_owned() }
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        if self.pattern.is_empty() {
+            return text.to_owned();
+        }
+
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while !rest.is_empty() {
+            match self.match_len(rest) {
+                Some(len) => {
+                    out.push_str(&self.replacement);
+                    rest = &rest[len..];
+                }
+                None => {
+                    let ch = rest.chars().next().unwrap();
+                    out.push(ch);
+                    rest = &rest[ch.len_utf8()..];
+                }
+            }
+        }
+        out
+    }
+
+    pub fn occurs_in(&self, text: &str) -> bool {
+        !self.pattern.is_empty()
+            && text.char_indices().any(|(i, _)| self.match_len(&text[i..]).is_some())
+    }
+
+    fn match_len(&self, text: &str) -> Option<usize> {
+        let mut len = 0;
+        for segment in &self.pattern {
+            let rest = &text[len..];
+            len += match segment {
+                Segment::Literal(literal) => {
+                    rest.starts_with(literal.as_str()).then_some(literal.len())?
+                }
+                Segment::Hash => {
+                    let hex = rest.bytes().take_while(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+                    (hex.count() >= 16).then_some(16)?
+                }
+                Segment::Num => match rest.bytes().take_while(u8::is_ascii_digit).count() {
+                    0 => return None,
+                    n => n,
+                },
+            };
+        }
+        Some(len)
+    }
+}
+
+fn path_to_string(dir: &Directory) -> String {
+    let dir = dir.to_string_lossy();
+    if cfg!(windows) {
+        let dir = dir.replace('\\', "/");
+        dir.strip_prefix("//?/").map_or_else(|| dir.clone(), str::to_owned)
+    } else {
+        dir.into_owned()
+    }
+}
+
+#[test]
+fn test_diagnostics() {
+    let project_dir = Directory::new("/home/ferris/project");
+    let ignored_lines = [Substitution::new("rustc {HASH}", "")];
+    let context = Context {
+        project_dir: &project_dir,
+        test_name: "trybuild007",
+        substitutions: &[],
+        ignored_lines: &ignored_lines,
+    };
+
+    let output = "\
+error[E0308]: mismatched types
+ --> /home/ferris/project/tests/ui/foo.rs:3:5\r
+note: in crate `trybuild007`
+note: built by rustc 0123456789abcdef (nightly)
+[1/2] building\r[2/2] building\rerror: aborting due to 1 previous error\r
+";
+    let expected = "\
+error[E0308]: mismatched types
+ --> $DIR/tests/ui/foo.rs:3:5
+note: in crate `$TEST`
+error: aborting due to 1 previous error
+";
+    assert_eq!(diagnostics(output, &context), expected);
+}
+
+#[test]
+fn test_escape_invalid_utf8() {
+    assert_eq!(escape_invalid_utf8(b"error: ok"), ("error: ok".to_owned(), false));
+    assert_eq!(escape_invalid_utf8(b"a\xFF\xC3b\xC3\xA9"), ("a\\xFF\\xC3b\u{e9}".to_owned(), true));
+}
+
+#[test]
+fn test_substitution() {
+    let substitution = Substitution::new("-{HASH}.rlib", "-HASH.rlib");
+    assert_eq!(
+        substitution.apply("libfoo-0123456789abcdef.rlib libbar-xyz.rlib"),
+        "libfoo-HASH.rlib libbar-xyz.rlib",
+    );
+
+    let substitution = Substitution::new("v{NUM}.{NUM}", "vX.Y");
+    assert_eq!(substitution.apply("serde v1.0 and v12.34.5"), "serde vX.Y and vX.Y.5");
+}
@@ -0,0 +1,162 @@
+use {
+    crate::{
+        error::{Error, Result},
+        Expected,
+    },
+    serde_derive::Serialize,
+    std::{collections::BTreeMap, fs, path::Path},
+};
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Record {
+    pub name: String,
+    pub path: String,
+    pub backend: String,
+    pub expected: &'static str,
+    pub outcome: &'static str,
+    pub error: Option<String>,
+    // Why a test with the outcome `skipped` didn't run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_reason: Option<&'static str>,
+    // Seconds spent building and running the test.
+    pub elapsed: f64,
+    // What was printed for the test, such as the diff of a mismatch.
+    #[serde(skip)]
+    pub output: String,
+}
+
+pub fn expected(expected: Expected) -> &'static str {
+    match expected {
+        Expected::Pass => "pass",
+        Expected::CompileFail => "compile-fail",
+        Expected::RunFail => "run-fail",
+        Expected::Warn => "warn",
+    }
+}
+
+pub fn write_json(path: &Path, records: &[Record]) -> Result<()> {
+    let json = serde_json::to_vec_pretty(records)
+        .map_err(|err| Error::WriteReport(path.to_owned(), err.into()))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|err| Error::WriteReport(path.to_owned(), err))?;
+    }
+    fs::write(path, json).map_err(|err| Error::WriteReport(path.to_owned(), err))
+}
+
+// One `<testcase>` per test and backend, with the backend in the class name so
+// that CI keeps the results of the backends apart.
+pub fn write_junit(path: &Path, records: &[Record]) -> Result<()> {
+    let failed = |record: &Record| matches!(record.outcome, "failed" | "flaky");
+    let failures = records.iter().filter(|record| failed(record)).count();
+    let skipped = records.iter().filter(|record| record.outcome == "skipped").count();
+    let time: f64 = records.iter().map(|record| record.elapsed).sum();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml += &format!(
+        "<testsuite name=\"trybuild\" tests=\"{}\" failures=\"{failures}\" skipped=\"{skipped}\" time=\"{time:.3}\">\n",
+        records.len(),
+    );
+    for record in records {
+        xml += &format!(
+            "  <testcase name=\"{}\" classname=\"trybuild.{}\" time=\"{:.3}\"",
+            xml_escape(&record.path),
+            xml_escape(&record.backend),
+            record.elapsed,
+        );
+        if failed(record) {
+            let message = record.error.as_deref().unwrap_or(record.outcome);
+            xml += &format!(
+                ">\n    <failure message=\"{}\" type=\"{}\">{}</failure>\n  </testcase>\n",
+                xml_escape(message),
+                record.outcome,
+                xml_escape(&record.output),
+            );
+        } else if let Some(reason) = record.skip_reason {
+            xml += &format!(">\n    <skipped message=\"{reason}\"/>\n  </testcase>\n");
+        } else {
+            xml += "/>\n";
+        }
+    }
+    xml += "</testsuite>\n";
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|err| Error::WriteReport(path.to_owned(), err))?;
+    }
+    fs::write(path, xml).map_err(|err| Error::WriteReport(path.to_owned(), err))
+}
+
+// Tests that passed under one backend and failed under another, each with
+// its outcome per backend. A test built at several opt levels counts as failed
+// under a backend if any of those builds failed.
+pub fn divergences(records: &[Record]) -> Vec<(&str, Vec<(&str, &'static str)>)> {
+    let mut outcomes = BTreeMap::<&str, Vec<(&str, &'static str)>>::new();
+    for record in records {
+        let outcome = match record.outcome {
+            "passed" => "passed",
+            "failed" | "flaky" => "failed",
+            _ => continue,
+        };
+        let backends = outcomes.entry(&record.path).or_default();
+        match backends.iter_mut().find(|(backend, _)| *backend == record.backend) {
+            Some((_, previous)) if outcome == "failed" => *previous = outcome,
+            Some(_) => {}
+            None => backends.push((&record.backend, outcome)),
+        }
+    }
+    outcomes
+        .into_iter()
+        .filter(|(_, backends)| backends.iter().any(|(_, outcome)| *outcome != backends[0].1))
+        .collect()
+}
+
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&apos;",
+            // Other control characters aren't allowed in XML 1.0 at all.
+            '\t' | '\n' | '\r' => escaped.push(ch),
+            _ if ch.is_control() => {}
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn test_xml_escape() {
+    assert_eq!(xml_escape("a < b && \"c\"\n"), "a &lt; b &amp;&amp; &quot;c&quot;\n");
+    assert_eq!(xml_escape("\u{1b}[31mred"), "[31mred");
+}
+
+#[test]
+fn test_divergences() {
+    let record = |path: &str, backend: &str, outcome| Record {
+        name: String::new(),
+        path: path.to_owned(),
+        backend: backend.to_owned(),
+        expected: "pass",
+        outcome,
+        error: None,
+        skip_reason: None,
+        elapsed: 0.0,
+        output: String::new(),
+    };
+    let records = [
+        record("a.rs", "cranelift", "passed"),
+        record("b.rs", "cranelift", "failed"),
+        record("c.rs", "cranelift", "passed"),
+        record("a.rs", "llvm", "passed"),
+        record("b.rs", "llvm", "passed"),
+        record("c.rs", "llvm", "skipped"),
+        record("b.rs", "llvm", "passed"),
+    ];
+    assert_eq!(
+        divergences(&records),
+        [("b.rs", vec![("cranelift", "failed"), ("llvm", "passed")])]
+    );
+}
@@ -1,32 +1,94 @@
 use {
+    crate::env,
     once_cell::sync::OnceCell,
     std::{
+        cell::{Cell, RefCell},
         io::{Result, Write},
         sync::{Mutex, MutexGuard, PoisonError},
     },
-    termcolor::{Color, ColorChoice, ColorSpec, StandardStream as Stream, WriteColor},
+    termcolor::{Buffer, BufferWriter, Color, ColorSpec, StandardStream, WriteColor},
 };
 
 static TERM: OnceCell<Mutex<Term>> = OnceCell::new();
+static WRITER: OnceCell<BufferWriter> = OnceCell::new();
+
+thread_local! {
+    static BUFFER: RefCell<Option<Term>> = const { RefCell::new(None) };
+    static LIVE: Cell<bool> = const { Cell::new(false) };
+}
 
 pub fn lock() -> MutexGuard<'static, Term> {
     TERM.get_or_init(|| Mutex::new(Term::new())).lock().unwrap_or_else(PoisonError::into_inner)
 }
 
+// Writes to the current thread's buffer if one is active, otherwise straight to
+// the shared terminal.
+pub fn with<R>(f: impl FnOnce(&mut Term) -> R) -> R {
+    BUFFER.with(|buffer| match &mut *buffer.borrow_mut() {
+        Some(term) => f(term),
+        None => f(&mut lock()),
+    })
+}
+
+// Collects everything printed by `f` on this thread and writes it out under a
+// single acquisition of the terminal lock. With `live`, which is for when no
+// other thread is printing, `flush` may also write out what is there so far.
+// Nothing is written out if `show` turns down the result. Also returns the
+// printed text without colors.
+pub fn with_buffer<R>(
+    live: bool,
+    f: impl FnOnce() -> R,
+    show: impl FnOnce(&R) -> bool,
+) -> (R, String) {
+    let writer = WRITER.get_or_init(|| BufferWriter::stderr(env::color()));
+    let prev = BUFFER.with(|buffer| buffer.replace(Some(Term::buffer(writer.buffer()))));
+    let prev_live = LIVE.replace(live);
+    let result = f();
+    LIVE.set(prev_live);
+    let mut term = BUFFER.with(|buffer| buffer.replace(prev)).unwrap();
+    if show(&result) {
+        term.write_out(writer);
+    }
+    (result, term.transcript)
+}
+
+// Runs `f` with everything it prints on this thread thrown away.
+pub fn discard<R>(f: impl FnOnce() -> R) -> R {
+    let writer = WRITER.get_or_init(|| BufferWriter::stderr(env::color()));
+    let prev = BUFFER.with(|buffer| buffer.replace(Some(Term::buffer(writer.buffer()))));
+    let prev_live = LIVE.replace(false);
+    let result = f();
+    LIVE.set(prev_live);
+    BUFFER.with(|buffer| buffer.replace(prev));
+    result
+}
+
+// Shows what has been printed so far, such as the line announcing a test
+// before a long build.
+pub fn flush() {
+    BUFFER.with(|buffer| match &mut *buffer.borrow_mut() {
+        Some(term) if LIVE.get() => term.write_out(WRITER.get().unwrap()),
+        Some(_) => {}
+        None => {
+            let _ = lock().flush();
+        }
+    });
+}
+
 pub fn bold() {
-    lock().set_color(ColorSpec::new().set_bold(true));
+    with(|term| term.set_color(ColorSpec::new().set_bold(true)));
 }
 
 pub fn color(color: Color) {
-    lock().set_color(ColorSpec::new().set_fg(Some(color)));
+    with(|term| term.set_color(ColorSpec::new().set_fg(Some(color))));
 }
 
 pub fn bold_color(color: Color) {
-    lock().set_color(ColorSpec::new().set_bold(true).set_fg(Some(color)));
+    with(|term| term.set_color(ColorSpec::new().set_bold(true).set_fg(Some(color))));
 }
 
 pub fn reset() {
-    lock().reset();
+    with(Term::reset);
 }
 
 #[deny(unused_macros)]
@@ -34,7 +96,9 @@ pub fn reset() {
 macro_rules! print {
     ($($args:tt)*) => {{
         use std::io::Write;
-        let _ = std::write!($crate::term::lock(), $($args)*);
+        $crate::term::with(|term| {
+            let _ = std::write!(term, $($args)*);
+        });
     }};
 }
 
@@ -43,7 +107,9 @@ macro_rules! print {
 macro_rules! println {
     ($($args:tt)*) => {{
         use std::io::Write;
-        let _ = std::writeln!($crate::term::lock(), $($args)*);
+        $crate::term::with(|term| {
+            let _ = std::writeln!(term, $($args)*);
+        });
     }};
 }
 
@@ -51,14 +117,43 @@ pub struct Term {
     spec: ColorSpec,
     stream: Stream,
     start_of_line: bool,
+    // Everything written to a buffer, including what was already written out.
+    transcript: String,
+}
+
+enum Stream {
+    Stderr(StandardStream),
+    Buffer(Buffer),
 }
 
 impl Term {
     fn new() -> Self {
         Term {
             spec: ColorSpec::new(),
-            stream: Stream::stderr(ColorChoice::Auto),
+            stream: Stream::Stderr(StandardStream::stderr(env::color())),
             start_of_line: true,
+            transcript: String::new(),
+        }
+    }
+
+    fn buffer(buffer: Buffer) -> Self {
+        Term {
+            spec: ColorSpec::new(),
+            stream: Stream::Buffer(buffer),
+            start_of_line: true,
+            transcript: String::new(),
+        }
+    }
+
+    fn write_out(&mut self, writer: &BufferWriter) {
+        if let Stream::Buffer(buffer) = &mut self.stream {
+            let _ = buffer.reset();
+            let mut term = lock();
+            let _ = term.stream.flush();
+            let _ = writer.print(buffer);
+            buffer.clear();
+            // The reset above dropped the current color.
+            self.start_of_line = true;
         }
     }
 
@@ -79,7 +174,10 @@ impl Write for Term {
     // Color one line at a time because Travis does not preserve color setting
     // across output lines.
     fn write(&mut self, mut buf: &[u8]) -> Result<usize> {
-        if self.spec.is_none() {
+        if let Stream::Buffer(_) = self.stream {
+            self.transcript += &String::from_utf8_lossy(buf);
+        }
+        if self.spec.is_none() || !self.stream.supports_color() {
             return self.stream.write(buf);
         }
 
@@ -108,3 +206,42 @@ impl Write for Term {
         self.stream.flush()
     }
 }
+
+impl Stream {
+    fn set_color(&mut self, spec: &ColorSpec) -> Result<()> {
+        match self {
+            Stream::Stderr(stream) => stream.set_color(spec),
+            Stream::Buffer(buffer) => buffer.set_color(spec),
+        }
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        match self {
+            Stream::Stderr(stream) => stream.reset(),
+            Stream::Buffer(buffer) => buffer.reset(),
+        }
+    }
+
+    fn supports_color(&self) -> bool {
+        match self {
+            Stream::Stderr(stream) => stream.supports_color(),
+            Stream::Buffer(buffer) => buffer.supports_color(),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Stream::Stderr(stream) => stream.write(buf),
+            Stream::Buffer(buffer) => buffer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Stream::Stderr(stream) => stream.flush(),
+            Stream::Buffer(buffer) => buffer.flush(),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+// Comparison of diagnostics that ignores the order they were reported in. The
+// output is split into blocks at blank lines, which is where one diagnostic
+// ends and the next begins, and the blocks are compared as a multiset.
+
+pub struct Difference<'a> {
+    // Blocks of the expected output that the actual output lacks.
+    pub missing: Vec<&'a str>,
+    // Blocks of the actual output that weren't expected.
+    pub extra: Vec<&'a str>,
+}
+
+impl Difference<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+pub fn compare<'a>(expected: &'a str, actual: &'a str) -> Difference<'a> {
+    let mut extra = blocks(actual);
+    let mut missing = Vec::new();
+    for block in blocks(expected) {
+        match extra.iter().position(|other| *other == block) {
+            Some(i) => {
+                extra.remove(i);
+            }
+            None => missing.push(block),
+        }
+    }
+    Difference { missing, extra }
+}
+
+fn blocks(output: &str) -> Vec<&str> {
+    let mut blocks = Vec::new();
+    let mut start = None;
+    let mut offset = 0;
+    for line in output.split_inclusive('\n') {
+        match start {
+            Some(begin) if line.trim().is_empty() => {
+                blocks.push(output[begin..offset].trim_end());
+                start = None;
+            }
+            None if !line.trim().is_empty() => start = Some(offset),
+            _ => {}
+        }
+        offset += line.len();
+    }
+    if let Some(begin) = start {
+        blocks.push(output[begin..].trim_end());
+    }
+    blocks
+}
+
+#[test]
+fn test_compare() {
+    let expected = "error: a\n --> x.rs:1:1\n\nerror: b\n\nerror: b\n";
+    assert!(compare(expected, "error: b\n\nerror: a\n --> x.rs:1:1\n\nerror: b\n").is_empty());
+
+    let difference = compare(expected, "error: b\n\n\nerror: c\n\nerror: a\n --> x.rs:1:1\n");
+    assert_eq!(difference.missing, ["error: b"]);
+    assert_eq!(difference.extra, ["error: c"]);
+}
@@ -0,0 +1,90 @@
+use {dissimilar::Chunk, std::panic};
+
+// An expected output file may list several acceptable outputs, separated by
+// lines consisting of just `---`. A test passes if it matches any of them.
+
+const SEPARATOR: &str = "---";
+
+pub struct Variants<'a> {
+    vec: Vec<&'a str>,
+}
+
+impl<'a> Variants<'a> {
+    pub fn parse(expected: &'a str) -> Self {
+        let mut vec = Vec::new();
+        let mut start = 0;
+        let mut offset = 0;
+        for line in expected.split_inclusive('\n') {
+            if line.trim_end() == SEPARATOR {
+                vec.push(&expected[start..offset]);
+                start = offset + line.len();
+            }
+            offset += line.len();
+        }
+        vec.push(&expected[start..]);
+        Variants { vec }
+    }
+
+    pub fn matches(&self, actual: &str) -> bool {
+        self.vec.contains(&actual)
+    }
+
+    pub fn any(&self, f: impl Fn(&str) -> bool) -> bool {
+        self.vec.iter().any(|variant| f(variant))
+    }
+
+    // The variant with the most text in common with `actual`, which is the one
+    // to diff against or to replace.
+    pub fn closest(&self, actual: &str) -> usize {
+        let common_len = |variant: &str| {
+            let diff =
+                panic::catch_unwind(|| dissimilar::diff(variant, actual)).unwrap_or_default();
+            let equal = diff.iter().map(|chunk| match chunk {
+                Chunk::Equal(common) => common.len(),
+                Chunk::Delete(_) | Chunk::Insert(_) => 0,
+            });
+            equal.sum::<usize>()
+        };
+        let mut best = (0, 0);
+        for (i, variant) in self.vec.iter().enumerate() {
+            let len = common_len(variant);
+            if len > best.1 {
+                best = (i, len);
+            }
+        }
+        best.0
+    }
+
+    pub fn get(&self, index: usize) -> &'a str {
+        self.vec[index]
+    }
+
+    // The file contents with the variant at `index` replaced by `actual` and
+    // the others left as they were.
+    pub fn replace(&self, index: usize, actual: &str) -> String {
+        let mut out = String::new();
+        for (i, variant) in self.vec.iter().enumerate() {
+            if i > 0 {
+                if !out.is_empty() && !out.ends_with('\n') {
+                    out.push('\n');
+                }
+                out.push_str(SEPARATOR);
+                out.push('\n');
+            }
+            out.push_str(if i == index { actual } else { variant });
+        }
+        out
+    }
+}
+
+#[test]
+fn test_variants() {
+    let expected = "error: a\n---\nerror: b\nerror: c\n---\nerror: d\n";
+    let variants = Variants::parse(expected);
+    assert!(variants.matches("error: b\nerror: c\n"));
+    assert!(!variants.matches("error: b\n"));
+    assert_eq!(variants.closest("error: c\n"), 1);
+    assert_eq!(variants.closest("error: dd\n"), 2);
+    assert_eq!(variants.replace(1, "error: e\n"), "error: a\n---\nerror: e\n---\nerror: d\n");
+    assert_eq!(variants.replace(0, "error: a\n"), expected);
+}